@@ -1,8 +1,40 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion};
 use rand::prelude::*;
 
-use chainmap::ChainMap;
+use chainmap::{BulkOptions, ChainMap, LocalChainMap};
+use std::alloc::{GlobalAlloc, Layout, System};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// System allocator that counts allocations, so that benchmarks can report them
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Print the average number of allocations made by one call to `f`
+fn report_allocations<F: FnMut(usize)>(name: &str, mut f: F) {
+    const CALLS: usize = 10_000;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for i in 0..CALLS {
+        f(i);
+    }
+    let count = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!("{}: {:.3} allocations per call", name, count as f64 / CALLS as f64);
+}
 
 fn insert(c: &mut Criterion) {
     let mut group = c.benchmark_group("Insert");
@@ -14,5 +46,71 @@ fn insert(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, insert);
+fn update_or_string(c: &mut Criterion) {
+    let mut group = c.benchmark_group("UpdateOrString");
+    let keys = (0..1000).map(|i| format!("variable_{}", i)).collect::<Vec<_>>();
+    let mut rng = rand::thread_rng();
+    let mut root = ChainMap::new();
+    for k in &keys {
+        root.insert(k.clone(), 0);
+    }
+    let mut ch = root.extend().extend();
+    // The key is never cloned: every binding already exists and is updated in place
+    report_allocations("UpdateOrString/existing", |i| ch.update_or(&keys[i % 1000], 1));
+    report_allocations("UpdateOrString/existing_str", |i| ch.update_or(keys[i % 1000].as_str(), 1));
+    group.bench_function("existing", |b| b.iter(|| ch.update_or(&keys[rng.gen_range(0, 1000)], rng.gen::<u32>())));
+    let mut log = root.extend_log();
+    for k in &keys {
+        log.insert(k.clone(), 0);
+    }
+    // The key is only cloned the first time a previous version is archived,
+    // the other allocations grow the list of versions
+    report_allocations("UpdateOrString/existing_log", |i| log.update_or(&keys[i % 1000], 1));
+    group.bench_function("existing_log", |b| b.iter(|| log.update_or(&keys[rng.gen_range(0, 1000)], rng.gen::<u32>())));
+    let mut h = HashMap::new();
+    for k in &keys {
+        h.insert(k.clone(), 0);
+    }
+    group.bench_function("hashmap", |b| b.iter(|| *h.get_mut(&keys[rng.gen_range(0, 1000)]).unwrap() = rng.gen::<u32>()));
+    group.finish();
+}
+
+fn bulk_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BulkLoad");
+    let bindings = (0..50_000).map(|i| (i, i * 2)).collect::<Vec<_>>();
+    group.bench_function("insert", |b| b.iter(|| {
+        let mut ch = ChainMap::new();
+        for &(k, v) in &bindings {
+            ch.insert(k, v);
+        }
+    }));
+    let presize = BulkOptions { presize: true, dedup: false };
+    group.bench_function("bulk_load", |b| b.iter(|| {
+        let mut ch = ChainMap::new();
+        ch.bulk_load(bindings.iter().copied(), presize)
+    }));
+    group.finish();
+}
+
+fn deep_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("DeepGet");
+    let mut rng = rand::thread_rng();
+    let mut ch = ChainMap::new();
+    let mut local = LocalChainMap::new();
+    for depth in 0..20 {
+        for k in 0..50 {
+            ch.insert(depth * 50 + k, k);
+            local.insert(depth * 50 + k, k);
+        }
+        ch = ch.extend();
+        local = local.extend();
+    }
+    group.bench_function("chainmap", |b| b.iter(|| ch.get(&rng.gen_range(0, 1000))));
+    group.bench_function("local", |b| b.iter(|| local.get(&rng.gen_range(0, 1000))));
+    let bloom = ch.clone_linked().bloom_filtered();
+    group.bench_function("bloom", |b| b.iter(|| bloom.get(&rng.gen_range(0, 1000))));
+    group.finish();
+}
+
+criterion_group!(benches, insert, update_or_string, bulk_load, deep_get);
 criterion_main!(benches);
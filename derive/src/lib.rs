@@ -0,0 +1,107 @@
+//! Derive macros for `chainmap`, reexported by `chainmap` under the `derive` feature
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Token, Type};
+
+/// A typed binding declared in `#[chain(name: Type, ...)]`
+struct KeyDecl {
+    name: Ident,
+    ty: Type,
+}
+
+impl Parse for KeyDecl {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let ty = input.parse()?;
+        Ok(Self { name, ty })
+    }
+}
+
+/// Generate typed accessors for bindings of a `ChainMap<String, _>` field
+///
+/// The field is marked with `#[chain(name: Type, ...)]`. For each declared binding,
+/// `fn name(&self) -> Option<Type>` reads the key `"name"` and parses it with `FromStr`,
+/// and `fn set_name(&mut self, Type)` stores it with `update_or` after formatting it with `ToString`.
+/// Keys are looked up as `&str`, without allocating.
+/// The values of the chain must implement `AsRef<str>` and `From<String>`.
+///
+/// ```ignore
+/// #[derive(ChainBacked)]
+/// struct Config {
+///     #[chain(timeout: u64, name: String)]
+///     env: ChainMap<String, String>,
+/// }
+/// ```
+#[proc_macro_derive(ChainBacked, attributes(chain))]
+pub fn derive_chain_backed(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "ChainBacked requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "ChainBacked can only be derived for structs",
+            ))
+        }
+    };
+    let mut accessors = Vec::new();
+    let mut found = false;
+    for field in fields {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("chain")) {
+            found = true;
+            let field_name = field.ident.as_ref().unwrap();
+            let decls =
+                attr.parse_args_with(Punctuated::<KeyDecl, Token![,]>::parse_terminated)?;
+            for KeyDecl { name, ty } in decls {
+                let key = name.to_string();
+                let setter = format_ident!("set_{}", name);
+                accessors.push(quote! {
+                    pub fn #name(&self) -> ::std::option::Option<#ty> {
+                        self.#field_name
+                            .get::<str>(#key)
+                            .and_then(|v| ::std::convert::AsRef::<str>::as_ref(&v).parse().ok())
+                    }
+
+                    pub fn #setter(&mut self, val: #ty) {
+                        self.#field_name.update_or::<str>(
+                            #key,
+                            ::std::convert::From::from(::std::string::ToString::to_string(&val)),
+                        )
+                    }
+                });
+            }
+        }
+    }
+    if !found {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "ChainBacked requires a field marked with #[chain(...)]",
+        ));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            #(#accessors)*
+        }
+    })
+}
@@ -0,0 +1,216 @@
+//! Interpreter for a tiny Lisp, with environments represented as a `ChainMap`
+//!
+//! - builtins live in a locked root layer,
+//! - `let` and function calls add a new scope on top of their environment,
+//! - closures keep a linked handle to the scope they were created in, which is enough
+//!   for recursion and for shared mutable state through `set!`,
+//! - `(trace x)` reports how `x` is bound, as a compiler would to decide how to access it.
+//!
+//! Run with `cargo run --example interpreter`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::vec;
+
+use chainmap::ChainMap;
+
+type Env = ChainMap<String, Value>;
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Num(i64),
+    Sym(String),
+    List(Vec<Expr>),
+}
+
+#[derive(Clone)]
+enum Value {
+    Num(i64),
+    Builtin(fn(&[i64]) -> i64),
+    Closure(Rc<Closure>),
+}
+
+struct Closure {
+    params: Vec<String>,
+    body: Expr,
+    env: Env,
+}
+
+impl Value {
+    fn num(&self) -> i64 {
+        match self {
+            Value::Num(n) => *n,
+            _ => panic!("expected a number, found {}", self),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Num(n) => write!(f, "{}", n),
+            Value::Builtin(_) => write!(f, "<builtin>"),
+            Value::Closure(c) => write!(f, "<lambda ({})>", c.params.join(" ")),
+        }
+    }
+}
+
+fn parse(tokens: &mut Peekable<vec::IntoIter<String>>) -> Expr {
+    match tokens.next().expect("unexpected end of input").as_str() {
+        "(" => {
+            let mut items = Vec::new();
+            while tokens.peek().map(String::as_str) != Some(")") {
+                items.push(parse(tokens));
+            }
+            tokens.next();
+            Expr::List(items)
+        }
+        ")" => panic!("unexpected )"),
+        atom => atom
+            .parse()
+            .map(Expr::Num)
+            .unwrap_or_else(|_| Expr::Sym(atom.to_string())),
+    }
+}
+
+fn parse_program(src: &str) -> Vec<Expr> {
+    let tokens = src
+        .lines()
+        .map(|line| line.split(';').next().unwrap())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('(', " ( ")
+        .replace(')', " ) ")
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut tokens = tokens.into_iter().peekable();
+    let mut exprs = Vec::new();
+    while tokens.peek().is_some() {
+        exprs.push(parse(&mut tokens));
+    }
+    exprs
+}
+
+fn eval(expr: &Expr, env: &mut Env) -> Value {
+    let items = match expr {
+        Expr::Num(n) => return Value::Num(*n),
+        Expr::Sym(name) => {
+            return env
+                .get(name)
+                .unwrap_or_else(|| panic!("unbound variable {}", name))
+        }
+        Expr::List(items) => items,
+    };
+    match items.as_slice() {
+        [Expr::Sym(kw), Expr::Sym(name), value] if kw == "define" => {
+            let value = eval(value, env);
+            env.insert(name.clone(), value.clone());
+            value
+        }
+        [Expr::Sym(kw), Expr::Sym(name), value] if kw == "set!" => {
+            let value = eval(value, env);
+            env.update(name, value.clone());
+            value
+        }
+        [Expr::Sym(kw), Expr::Sym(name), value, body] if kw == "let" => {
+            let mut frame = HashMap::new();
+            frame.insert(name.clone(), eval(value, env));
+            eval(body, &mut env.extend_with(frame))
+        }
+        [Expr::Sym(kw), Expr::List(params), body] if kw == "lambda" => {
+            let params = params
+                .iter()
+                .map(|param| match param {
+                    Expr::Sym(name) => name.clone(),
+                    _ => panic!("parameters must be names"),
+                })
+                .collect();
+            Value::Closure(Rc::new(Closure {
+                params,
+                body: body.clone(),
+                env: env.clone_linked(),
+            }))
+        }
+        [Expr::Sym(kw), cond, then, other] if kw == "if" => {
+            if eval(cond, env).num() != 0 {
+                eval(then, env)
+            } else {
+                eval(other, env)
+            }
+        }
+        [Expr::Sym(kw), Expr::Sym(name)] if kw == "trace" => {
+            println!("  {} is {:?}", name, env.classify(name));
+            eval(&items[1], env)
+        }
+        [f, args @ ..] => {
+            let f = eval(f, env);
+            let args = args.iter().map(|arg| eval(arg, env)).collect();
+            apply(f, args)
+        }
+        [] => panic!("empty application"),
+    }
+}
+
+fn apply(f: Value, args: Vec<Value>) -> Value {
+    match f {
+        Value::Builtin(op) => Value::Num(op(&args.iter().map(Value::num).collect::<Vec<_>>())),
+        Value::Closure(closure) => {
+            assert_eq!(closure.params.len(), args.len(), "wrong number of arguments");
+            let frame = closure.params.iter().cloned().zip(args).collect();
+            eval(&closure.body, &mut closure.env.extend_with(frame))
+        }
+        Value::Num(n) => panic!("{} is not a function", n),
+    }
+}
+
+fn builtins() -> Env {
+    let mut root = HashMap::new();
+    let mut def = |name: &str, op: fn(&[i64]) -> i64| {
+        root.insert(String::from(name), Value::Builtin(op));
+    };
+    def("+", |args| args.iter().sum());
+    def("*", |args| args.iter().product());
+    def("-", |args| args[0] - args[1..].iter().sum::<i64>());
+    def("<", |args| (args[0] < args[1]) as i64);
+    ChainMap::new_with(root).locked()
+}
+
+const PROGRAM: &str = "
+    (define square (lambda (x) (* x x)))
+    (define make-adder (lambda (n) (lambda (x) (+ x (trace n)))))
+    (define add5 (make-adder 5))
+    (add5 (square 3))
+
+    ; `fact` is bound after the lambda is created, but in the scope it captured
+    (define fact (lambda (n) (if (< n 2) 1 (* n (fact (- n 1))))))
+    (fact 10)
+
+    ; every counter updates the `count` of its own `let` scope
+    (define make-counter (lambda () (let count 0 (lambda () (set! count (+ count 1))))))
+    (define tick (make-counter))
+    (define tock (make-counter))
+    (tick) (tick) (tock) (tick)
+
+    (let y 4 (+ (trace y) ((trace square) 2) ((trace +) 0)))
+";
+
+fn main() {
+    let mut env = builtins().extend();
+    let mut results = Vec::new();
+    for expr in parse_program(PROGRAM) {
+        let value = eval(&expr, &mut env);
+        println!("{}", value);
+        results.push(value.to_string());
+    }
+    assert_eq!(results[3], "14");
+    assert_eq!(results[5], "3628800");
+    assert_eq!(results[9..13], ["1", "2", "1", "3"]);
+    assert_eq!(results[13], "8");
+    // The builtins cannot be redefined in place, only shadowed
+    assert!(builtins().is_locked());
+    assert!(env.local_get(&String::from("fact")).is_some());
+    assert!(env.local_get(&String::from("+")).is_none());
+}
@@ -0,0 +1,52 @@
+//! Grouped modifications of the toplevel
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{ChainMap, Recover};
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Modify the bindings of the toplevel all at once
+    ///
+    /// `f` works on a copy of the toplevel, which replaces it only once `f` returns:
+    /// if `f` panics the toplevel is left unchanged, and since no lock is held while `f` runs
+    /// the layer is not poisoned either. Modifications made to the toplevel through other handles
+    /// while `f` runs are overwritten. Modifications made by `f` are not versioned by `extend_log`.
+    /// # Panics
+    /// - if toplevel map is locked
+    /// - if the modified toplevel would exceed the quota, see `set_quota`
+    pub fn batch<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut HashMap<K, V>) -> R,
+    {
+        if self.is_locked() {
+            panic!("Map is locked, could not run batch");
+        }
+        let mut staged = self.top().elem.lock_recover().clone();
+        let before = staged.len();
+        let res = f(&mut staged);
+        if let Err(err) = self.check_quota(staged.len().saturating_sub(before)) {
+            panic!("{}, could not run batch", err);
+        }
+        let node = self.top();
+        for key in staged.keys() {
+            node.forget(key);
+        }
+        *node.elem.lock_recover() = staged;
+        node.touch();
+        res
+    }
+
+    /// Check that no invariant is broken, see `validate`
+    ///
+    /// Intended to be called after catching a panic, e.g. at an FFI boundary:
+    /// a panic while the lock of a layer is held, e.g. through `entry`, makes the chain
+    /// inconsistent, while a panic inside `batch` does not.
+    pub fn is_consistent(&self) -> bool {
+        self.validate().is_empty()
+    }
+}
@@ -0,0 +1,136 @@
+//! Per-layer Bloom filters, to skip the layers that cannot bind a key
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::{AtomicUsize, ChainMap, Node, Ordering, Recover};
+
+const WORDS: usize = 16;
+const BITS: usize = WORDS * usize::BITS as usize;
+
+/// Two bits per key, in a fixed-size bitset
+pub(crate) struct Bloom {
+    words: [AtomicUsize; WORDS],
+    /// Generation of the layer that the bits account for, they cannot be trusted otherwise
+    generation: AtomicUsize,
+}
+
+impl Bloom {
+    fn new() -> Self {
+        Self {
+            words: std::array::from_fn(|_| AtomicUsize::new(0)),
+            generation: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    fn bits<Q: Hash + ?Sized>(key: &Q) -> [usize; 2] {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        [hash as usize % BITS, (hash >> 32) as usize % BITS]
+    }
+
+    fn add<Q: Hash + ?Sized>(&self, key: &Q) {
+        for bit in Self::bits(key) {
+            self.words[bit / usize::BITS as usize]
+                .fetch_or(1 << (bit % usize::BITS as usize), Ordering::Relaxed);
+        }
+    }
+
+    fn may_contain<Q: Hash + ?Sized>(&self, key: &Q) -> bool {
+        Self::bits(key).iter().all(|bit| {
+            self.words[bit / usize::BITS as usize].load(Ordering::Relaxed)
+                & (1 << (bit % usize::BITS as usize))
+                != 0
+        })
+    }
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Start maintaining a Bloom filter for this layer
+    pub(crate) fn enable_bloom(&self) {
+        self.bloom.get_or_init(Bloom::new);
+    }
+
+    pub(crate) fn has_bloom(&self) -> bool {
+        self.bloom.get().is_some()
+    }
+
+    /// Same as `touch`, for a modification that binds or unbinds only `key`
+    ///
+    /// Keeps the Bloom filter up to date instead of invalidating it. Since the filter only
+    /// ever gains bits, this may be called before the modification, while the layer is locked.
+    pub(crate) fn touch_key<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.bloom.get() {
+            Some(bloom)
+                if bloom.generation.load(Ordering::Relaxed)
+                    == self.generation.load(Ordering::Relaxed) =>
+            {
+                bloom.add(key);
+                self.touch();
+                bloom
+                    .generation
+                    .store(self.generation.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+            _ => self.touch(),
+        }
+    }
+
+    /// Whether this layer may bind `key`, only false if its Bloom filter rules it out
+    ///
+    /// A filter left stale by a modification of the layer is rebuilt first.
+    pub(crate) fn may_contain<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bloom = match self.bloom.get() {
+            Some(bloom) => bloom,
+            None => return true,
+        };
+        // Read before rebuilding: a modification made in between makes the filter look stale
+        let generation = self.generation.load(Ordering::Relaxed);
+        if bloom.generation.load(Ordering::Relaxed) != generation {
+            let layer = self.elem.lock_recover();
+            for word in &bloom.words {
+                word.store(0, Ordering::Relaxed);
+            }
+            for k in layer.keys() {
+                bloom.add(k);
+            }
+            bloom.generation.store(generation, Ordering::Relaxed);
+        }
+        bloom.may_contain(key)
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Let lookups skip the layers that a Bloom filter shows cannot bind the key
+    ///
+    /// Makes lookups of keys bound deep in the chain, or not at all, cheaper: most layers
+    /// are then ruled out without being locked. Filters have a fixed size, so they lose their
+    /// effect on layers with thousands of bindings. Applies to every layer of the chain,
+    /// and to layers later created above it.
+    pub fn bloom_filtered(self) -> Self {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            m.enable_bloom();
+            r = m.next.as_ref();
+        }
+        self
+    }
+
+    pub fn is_bloom_filtered(&self) -> bool {
+        self.link().has_bloom()
+    }
+}
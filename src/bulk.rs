@@ -0,0 +1,114 @@
+//! Loading large amounts of bindings at once
+
+use std::collections::hash_map;
+use std::hash::Hash;
+
+use crate::{ChainMap, Node, Recover};
+
+/// Settings of `ChainMap::bulk_load`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulkOptions {
+    /// Reserve room in the toplevel for the lower bound of the iterator's `size_hint`
+    pub presize: bool,
+    /// Skip bindings that the chain already resolves to an equal value from a lower layer
+    ///
+    /// Keys hidden by a tombstone of the toplevel are never skipped.
+    pub dedup: bool,
+}
+
+/// Summary of a call to `ChainMap::bulk_load`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulkStats {
+    /// Bindings for keys that were not bound in the toplevel
+    pub inserted: usize,
+    /// Bindings that replaced a value of the toplevel
+    pub overwritten: usize,
+    /// Bindings skipped because `dedup` found them redundant
+    pub deduplicated: usize,
+    /// Bindings refused because the toplevel is locked or the quota is reached
+    pub rejected: usize,
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash,
+    V: PartialEq,
+{
+    /// Insert every binding of `iter` into the toplevel, never panicking
+    ///
+    /// Meant for preloading many bindings: the toplevel is locked only once for the whole
+    /// operation. Instead of panicking, bindings that cannot be inserted are counted as rejected:
+    /// all of them if the toplevel is locked, and those of new keys once the quota is reached.
+    /// Overwriting a key already bound in the toplevel is always allowed.
+    pub fn bulk_load<I>(&mut self, iter: I, options: BulkOptions) -> BulkStats
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let iter = iter.into_iter();
+        let mut stats = BulkStats::default();
+        if self.is_locked() {
+            stats.rejected = iter.count();
+            return stats;
+        }
+        let mut headroom = self.quota_headroom();
+        let node = self.top();
+        let mut layer = node.elem.lock_recover();
+        if options.presize {
+            layer.reserve(iter.size_hint().0);
+        }
+        for (key, val) in iter {
+            let bound = layer.contains_key(&key);
+            if options.dedup
+                && !bound
+                && !node.hides(&key)
+                && resolves_below(node, &key, &val)
+            {
+                stats.deduplicated += 1;
+                continue;
+            }
+            if !bound {
+                match &mut headroom {
+                    Some(0) => {
+                        stats.rejected += 1;
+                        continue;
+                    }
+                    Some(n) => *n -= 1,
+                    None => (),
+                }
+            }
+            node.forget(&key);
+            match layer.entry(key) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    let old = entry.insert(val);
+                    node.archive(entry.key(), old);
+                    stats.overwritten += 1;
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(val);
+                    stats.inserted += 1;
+                }
+            }
+        }
+        node.touch();
+        stats
+    }
+}
+
+/// Whether the layers below `node` resolve `key` to `val`
+fn resolves_below<K, V>(node: &Node<K, V>, key: &K, val: &V) -> bool
+where
+    K: Eq + Hash,
+    V: PartialEq,
+{
+    let mut r = node.next.as_ref();
+    while let Some(m) = r {
+        if let Some(bound) = m.elem.lock_recover().get(key) {
+            return bound == val;
+        }
+        if m.hides(key) {
+            return false;
+        }
+        r = m.next.as_ref();
+    }
+    false
+}
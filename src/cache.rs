@@ -0,0 +1,170 @@
+//! Layers that keep a copy of the values resolved through them
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{ChainMap, Mutex, Node, Ordering, Recover};
+
+/// Number of lookups answered by a caching layer, see `ChainMap::extend_cache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups served from a copy held by the layer
+    pub hits: usize,
+    /// Lookups that had to go further down the chain
+    pub misses: usize,
+}
+
+/// Copies held by a caching layer, separate from its own bindings
+#[derive(Clone)]
+pub(crate) struct ReadCache<K, V> {
+    pub(crate) entries: HashMap<K, Cached<V>>,
+    pub(crate) stats: CacheStats,
+    /// Set by `extend_cache`, so that keys can be copied without requiring `K: Clone`
+    copy_key: fn(&K) -> K,
+}
+
+/// Copy of a value, along with the generations of the layers it was resolved through
+///
+/// The first generation is that of the layer right below the caching layer, the last one
+/// that of the layer that owns the binding. The copy is stale as soon as any of them changes.
+#[derive(Clone)]
+pub(crate) struct Cached<V> {
+    val: V,
+    generations: Vec<usize>,
+}
+
+impl<K, V> ReadCache<K, V> {
+    fn new(copy_key: fn(&K) -> K) -> Self {
+        Self {
+            entries: HashMap::new(),
+            stats: CacheStats::default(),
+            copy_key,
+        }
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Create a new scope that caches values read through it
+    ///
+    /// The first `get` of a key bound lower in the chain copies the value into the new layer,
+    /// so that later lookups of the same key stop there instead of going down the whole chain.
+    /// Copies are kept apart from the bindings of the layer: they are not seen by `local_get`,
+    /// `collect` and the like, and `update` still writes to the layer that owns the binding.
+    ///
+    /// A copy is only used as long as none of the layers it was resolved through has been
+    /// modified since, by any handle. Checking this does not lock the lower layers.
+    pub fn extend_cache(&self) -> Self {
+        let mut node = Node::new(HashMap::new(), Some(self.settled_link().clone()), false);
+        node.cache = Some(Mutex::new(ReadCache::new(K::clone)));
+        self.child(node)
+    }
+
+    /// Hits and misses of the toplevel, if it was created by `extend_cache`
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        let cache = self.link().cache.as_ref()?;
+        Some(cache.lock_recover().stats)
+    }
+
+    /// Discard the copies of `key` held by all caching layers of the chain
+    ///
+    /// Stale copies are never used, this only frees them early.
+    pub fn invalidate<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            m.forget(key);
+            r = m.next.as_ref();
+        }
+    }
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Look for a copy of `key`, if this is a caching layer
+    ///
+    /// If `path` is given, the generations that the copy depends on are appended to it.
+    pub(crate) fn cached<Q>(&self, key: &Q, path: Option<&mut Vec<usize>>) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut cache = self.cache.as_ref()?.lock_recover();
+        let found = match cache.entries.get(key) {
+            Some(entry) if self.unchanged_below(&entry.generations) => {
+                if let Some(path) = path {
+                    path.extend_from_slice(&entry.generations);
+                }
+                Some(entry.val.clone())
+            }
+            Some(_) => {
+                cache.entries.remove(key);
+                None
+            }
+            None => None,
+        };
+        match found {
+            Some(val) => {
+                cache.stats.hits += 1;
+                Some(val)
+            }
+            None => {
+                cache.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Whether the layers below this one still have the given generations
+    fn unchanged_below(&self, generations: &[usize]) -> bool {
+        let mut r = self.next.as_ref();
+        generations.iter().all(|&generation| match r {
+            Some(m) => {
+                r = m.next.as_ref();
+                m.generation.load(Ordering::Relaxed) == generation
+            }
+            None => false,
+        })
+    }
+
+    /// Keep a copy of a value resolved lower in the chain, if this is a caching layer
+    ///
+    /// `generations` are those of the layers below this one down to the owner of the binding,
+    /// read before they were looked up.
+    pub(crate) fn remember(&self, key: &K, val: &V, generations: &[usize])
+    where
+        V: Clone,
+    {
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock_recover();
+            let key = (cache.copy_key)(key);
+            let entry = Cached {
+                val: val.clone(),
+                generations: generations.to_vec(),
+            };
+            cache.entries.insert(key, entry);
+        }
+    }
+
+    /// Drop the copy of `key`, if this is a caching layer
+    pub(crate) fn forget<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(cache) = &self.cache {
+            cache.lock_recover().entries.remove(key);
+        }
+    }
+}
+
@@ -0,0 +1,71 @@
+//! Source of time for time-dependent features
+
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where time-dependent features such as `ChainMap::start_snapshotting_with_clock` read the time
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The actual time, as given by `Instant::now`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for deterministic tests
+///
+/// Share it through an `Rc` or an `Arc` to keep advancing it after handing it out.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+impl<C> Clock for Rc<C>
+where
+    C: Clock + ?Sized,
+{
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+impl<C> Clock for Arc<C>
+where
+    C: Clock + ?Sized,
+{
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
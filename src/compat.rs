@@ -0,0 +1,267 @@
+//! Common interface of `HashMap` and `ChainMap`
+
+use std::collections::hash_map::{self, HashMap};
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+
+use crate::{ChainMap, Entry, Iter, MutexGuard, Recover, ValueGuardMut};
+
+/// Minimal map interface, implemented for both `HashMap` and `ChainMap`
+///
+/// Values are returned by clone rather than by reference, since a `ChainMap`
+/// cannot lend references into its layers without holding their locks.
+/// Insertions and removals only ever concern the toplevel of a `ChainMap`.
+pub trait MapLike<K, V> {
+    /// Iterator returned by `iter`
+    type Iter<'a>: Iterator<Item = (K, V)>
+    where
+        Self: 'a;
+
+    /// Entry returned by `entry`
+    type Entry<'a>: MapEntry<K, V>
+    where
+        Self: 'a;
+
+    /// Value associated with `key`
+    fn get(&self, key: &K) -> Option<V>;
+
+    /// Stored key and value associated with `key`
+    fn get_key_value(&self, key: &K) -> Option<(K, V)>;
+
+    fn contains_key(&self, key: &K) -> bool;
+
+    /// Number of accessible keys
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Create a new binding, returning the value it replaced if any
+    fn insert(&mut self, key: K, val: V) -> Option<V>;
+
+    /// Only keep bindings for which `f` returns `true`
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool;
+
+    /// Iterate over all accessible bindings, in arbitrary order
+    fn iter(&self) -> Self::Iter<'_>;
+
+    /// In-place access to the binding of `key`
+    fn entry(&mut self, key: K) -> Self::Entry<'_>;
+}
+
+/// Common interface of the entries of `HashMap` and `ChainMap`, see `MapLike::entry`
+pub trait MapEntry<K, V>: Sized {
+    /// Mutable access to the value, as handed out by `or_insert`
+    type Value: DerefMut<Target = V>;
+
+    fn key(&self) -> &K;
+
+    /// Bind `default` if the key is not already bound
+    fn or_insert(self, default: V) -> Self::Value {
+        self.or_insert_with(|| default)
+    }
+
+    /// Bind the result of `default` if the key is not already bound
+    fn or_insert_with<F>(self, default: F) -> Self::Value
+    where
+        F: FnOnce() -> V;
+}
+
+impl<'a, K, V> MapEntry<K, V> for hash_map::Entry<'a, K, V> {
+    type Value = &'a mut V;
+
+    fn key(&self) -> &K {
+        hash_map::Entry::key(self)
+    }
+
+    fn or_insert_with<F>(self, default: F) -> &'a mut V
+    where
+        F: FnOnce() -> V,
+    {
+        hash_map::Entry::or_insert_with(self, default)
+    }
+}
+
+impl<'a, K, V> MapEntry<K, V> for Entry<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Value = ValueGuardMut<'a, K, V>;
+
+    fn key(&self) -> &K {
+        Entry::key(self)
+    }
+
+    fn or_insert_with<F>(self, default: F) -> ValueGuardMut<'a, K, V>
+    where
+        F: FnOnce() -> V,
+    {
+        Entry::or_insert_with(self, default)
+    }
+}
+
+impl<K, V> MapLike<K, V> for HashMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Iter<'a>
+        = std::iter::Map<hash_map::Iter<'a, K, V>, fn((&K, &V)) -> (K, V)>
+    where
+        Self: 'a;
+    type Entry<'a>
+        = hash_map::Entry<'a, K, V>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<V> {
+        HashMap::get(self, key).cloned()
+    }
+
+    fn get_key_value(&self, key: &K) -> Option<(K, V)> {
+        HashMap::get_key_value(self, key).map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        HashMap::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn insert(&mut self, key: K, val: V) -> Option<V> {
+        HashMap::insert(self, key, val)
+    }
+
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        HashMap::retain(self, f)
+    }
+
+    fn iter(&self) -> Self::Iter<'_> {
+        HashMap::iter(self).map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    fn entry(&mut self, key: K) -> hash_map::Entry<'_, K, V> {
+        HashMap::entry(self, key)
+    }
+}
+
+impl<K, V> MapLike<K, V> for ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Iter<'a>
+        = Iter<'a, K, V>
+    where
+        Self: 'a;
+    type Entry<'a>
+        = Entry<'a, K, V>
+    where
+        Self: 'a;
+
+    fn get(&self, key: &K) -> Option<V> {
+        ChainMap::get(self, key)
+    }
+
+    fn get_key_value(&self, key: &K) -> Option<(K, V)> {
+        ChainMap::get_key_value(self, key)
+    }
+
+    fn contains_key(&self, key: &K) -> bool {
+        ChainMap::contains_key(self, key)
+    }
+
+    fn len(&self) -> usize {
+        ChainMap::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        ChainMap::is_empty(self)
+    }
+
+    fn insert(&mut self, key: K, val: V) -> Option<V> {
+        ChainMap::insert(self, key, val)
+    }
+
+    fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        ChainMap::retain(self, f)
+    }
+
+    fn iter(&self) -> Iter<'_, K, V> {
+        ChainMap::iter(self)
+    }
+
+    fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        ChainMap::entry(self, key)
+    }
+}
+
+/// The toplevel of a `ChainMap` seen as a plain `HashMap`, obtained from `ChainMap::as_flat_mut`
+///
+/// Holds the toplevel's lock for as long as it is alive.
+pub struct FlatAdapter<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    layer: MutexGuard<'a, HashMap<K, V>>,
+}
+
+impl<'a, K, V> Deref for FlatAdapter<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &HashMap<K, V> {
+        &self.layer
+    }
+}
+
+impl<'a, K, V> DerefMut for FlatAdapter<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    fn deref_mut(&mut self) -> &mut HashMap<K, V> {
+        &mut self.layer
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Borrow the toplevel as a `HashMap`
+    ///
+    /// Meant for code bases that switch from a `HashMap` to a `ChainMap`: call sites that
+    /// only ever dealt with a single map can keep using the `HashMap` API unchanged.
+    /// Lower layers are neither visible nor modified through the adapter.
+    /// # Panics
+    /// - if toplevel map is locked
+    /// - if the handle has a quota, which the adapter could not enforce
+    pub fn as_flat_mut(&mut self) -> FlatAdapter<'_, K, V> {
+        if self.is_locked() {
+            panic!("Map is locked, could not borrow as flat map");
+        }
+        if self.quota.is_some() {
+            panic!("Map has a quota, could not borrow as flat map");
+        }
+        // The adapter grants mutable access, we have to assume it will be used
+        let node = self.top();
+        node.touch();
+        FlatAdapter {
+            layer: node.elem.lock_recover(),
+        }
+    }
+}
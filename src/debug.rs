@@ -0,0 +1,89 @@
+//! Structured description of a chain for debuggers
+
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::{ChainMap, Recover};
+
+/// Role of a scope, in the terms of the Debug Adapter Protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeKind {
+    /// The scope of the handle itself
+    Local,
+    /// An enclosing scope other than the root
+    Closure,
+    /// The scope of the root layer
+    Global,
+}
+
+/// A binding as presented to a debugger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugVariable {
+    pub name: String,
+    pub value: String,
+    /// Whether a scope closer to the handle binds the same key
+    pub shadowed: bool,
+}
+
+/// A group of layers linked by fallthrough, see `ChainMap::debug_scopes`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugScope {
+    pub name: String,
+    pub kind: ScopeKind,
+    /// Sorted by name
+    pub variables: Vec<DebugVariable>,
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + fmt::Debug,
+    V: Clone + fmt::Debug,
+{
+    /// Describe every scope of the chain, from the handle's own to the root's
+    ///
+    /// Scopes are delimited as in `classify`: the layers of a scope are linked by fallthrough.
+    /// Keys and values are rendered with their `Debug` implementation.
+    pub fn debug_scopes(&self) -> Vec<DebugScope> {
+        let mut scopes = Vec::new();
+        let mut outer = HashSet::new();
+        let mut hidden = HashSet::new();
+        let mut r = Some(self.link());
+        while r.is_some() {
+            let depth = scopes.len();
+            let mut inner = HashSet::new();
+            let mut variables = Vec::new();
+            let mut is_root = false;
+            while let Some(m) = r {
+                for (k, v) in m.elem.lock_recover().iter() {
+                    if !hidden.contains(k) && inner.insert(k.clone()) {
+                        variables.push(DebugVariable {
+                            name: format!("{:?}", k),
+                            value: format!("{:?}", v),
+                            shadowed: outer.contains(k),
+                        });
+                    }
+                }
+                m.hidden_keys(&mut hidden);
+                r = m.next.as_ref();
+                is_root = r.is_none();
+                if !m.fallthrough {
+                    break;
+                }
+            }
+            variables.sort_by(|a, b| a.name.cmp(&b.name));
+            let (kind, name) = match (depth, is_root) {
+                (0, _) => (ScopeKind::Local, String::from("Local")),
+                (_, true) => (ScopeKind::Global, String::from("Global")),
+                (depth, false) => (ScopeKind::Closure, format!("Closure {}", depth)),
+            };
+            scopes.push(DebugScope {
+                name,
+                kind,
+                variables,
+            });
+            outer.extend(inner);
+        }
+        scopes
+    }
+}
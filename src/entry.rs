@@ -0,0 +1,160 @@
+//! In-place access to the bindings of the toplevel
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut};
+
+use crate::{MutexGuard, Node, QuotaExceeded, Recover};
+
+/// A view into a single key of the toplevel, obtained from `ChainMap::entry`
+///
+/// Holds the toplevel's lock for as long as it is alive.
+pub struct Entry<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub(crate) layer: MutexGuard<'a, HashMap<K, V>>,
+    pub(crate) node: &'a Node<K, V>,
+    pub(crate) key: K,
+    /// Set if binding the key would exceed the quota
+    pub(crate) full: Option<QuotaExceeded>,
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Key this entry was created for
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Whether the toplevel already binds the key
+    pub fn is_occupied(&self) -> bool {
+        self.layer.contains_key(&self.key)
+    }
+
+    /// Bind `default` if the toplevel does not already bind the key
+    pub fn or_insert(self, default: V) -> ValueGuardMut<'a, K, V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Bind the result of `default` if the toplevel does not already bind the key
+    pub fn or_insert_with<F>(mut self, default: F) -> ValueGuardMut<'a, K, V>
+    where
+        F: FnOnce() -> V,
+    {
+        if !self.layer.contains_key(&self.key) {
+            self.check_quota();
+            self.layer.insert(self.key.clone(), default());
+        }
+        ValueGuardMut {
+            layer: self.layer,
+            key: self.key,
+        }
+    }
+
+    /// Bind a copy of the value visible lower in the chain if the toplevel does not already bind the key
+    ///
+    /// Later modifications through the returned guard only affect the toplevel.
+    /// Returns `None` if the key is not bound anywhere in the chain, or is hidden by a tombstone.
+    pub fn or_copy_up(mut self) -> Option<ValueGuardMut<'a, K, V>> {
+        if !self.layer.contains_key(&self.key) {
+            let mut m = self.node;
+            let val = loop {
+                if m.hides(&self.key) {
+                    return None;
+                }
+                m = m.next.as_ref()?;
+                if let Some(val) = m.elem.lock_recover().get(&self.key) {
+                    break val.clone();
+                }
+            };
+            self.check_quota();
+            self.layer.insert(self.key.clone(), val);
+        }
+        Some(ValueGuardMut {
+            layer: self.layer,
+            key: self.key,
+        })
+    }
+
+    fn check_quota(&self) {
+        if let Some(err) = &self.full {
+            panic!("{}, could not insert", err);
+        }
+    }
+
+    /// Modify the value in place if the toplevel binds the key
+    pub fn and_modify<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(&mut V),
+    {
+        if let Some(val) = self.layer.get_mut(&self.key) {
+            f(val);
+        }
+        self
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Default,
+{
+    /// Bind `V::default()` if the toplevel does not already bind the key
+    pub fn or_default(self) -> ValueGuardMut<'a, K, V> {
+        self.or_insert_with(V::default)
+    }
+}
+
+/// Shared access to a value, holding the lock of the layer that contains it
+pub struct ValueGuard<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) layer: MutexGuard<'a, HashMap<K, V>>,
+    pub(crate) key: K,
+}
+
+impl<'a, K, V> Deref for ValueGuard<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.layer[&self.key]
+    }
+}
+
+/// Mutable access to a value, holding the lock of the layer that contains it
+pub struct ValueGuardMut<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) layer: MutexGuard<'a, HashMap<K, V>>,
+    pub(crate) key: K,
+}
+
+impl<'a, K, V> Deref for ValueGuardMut<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.layer[&self.key]
+    }
+}
+
+impl<'a, K, V> DerefMut for ValueGuardMut<'a, K, V>
+where
+    K: Eq + Hash,
+{
+    fn deref_mut(&mut self) -> &mut V {
+        self.layer.get_mut(&self.key).unwrap()
+    }
+}
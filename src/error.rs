@@ -0,0 +1,55 @@
+//! Reasons for a modification of a chain to be refused
+
+use std::error::Error;
+use std::fmt;
+
+use crate::QuotaExceeded;
+
+/// Error of the fallible counterparts of `insert` and `update`, and of `squash_into_parent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChainMapError {
+    /// The toplevel is locked
+    Locked,
+    /// The first layer that binds the key is locked
+    KeyLocked,
+    /// The key is only bound behind a write-protected layer
+    ReadOnlyBarrier,
+    /// The key is not bound anywhere
+    KeyMissing,
+    /// The new binding would exceed the quota, see `ChainMap::set_quota`
+    Quota(QuotaExceeded),
+    /// The toplevel is the root of the chain
+    NoParent,
+    /// The toplevel is also used by another handle or layer
+    Shared,
+}
+
+impl fmt::Display for ChainMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainMapError::Locked => write!(f, "Map is locked"),
+            ChainMapError::KeyLocked => write!(f, "Key is locked"),
+            ChainMapError::ReadOnlyBarrier => write!(f, "Key is behind a write-protected layer"),
+            ChainMapError::KeyMissing => write!(f, "Key does not exist"),
+            ChainMapError::Quota(err) => err.fmt(f),
+            ChainMapError::NoParent => write!(f, "Map has no parent layer"),
+            ChainMapError::Shared => write!(f, "Layer is shared"),
+        }
+    }
+}
+
+impl Error for ChainMapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ChainMapError::Quota(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<QuotaExceeded> for ChainMapError {
+    fn from(err: QuotaExceeded) -> Self {
+        ChainMapError::Quota(err)
+    }
+}
@@ -0,0 +1,162 @@
+//! Handle to a chain that can cross the boundary of a separately compiled plugin
+
+use std::ffi::c_void;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::slice;
+use std::str;
+
+use crate::ChainMap;
+
+/// Borrowed UTF-8 string, as passed through the vtable of a `FfiChain`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl FfiStr {
+    pub fn new(s: &str) -> Self {
+        Self {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must point to `len` bytes that stay valid for `'a`
+    unsafe fn as_str<'a>(self) -> Option<&'a str> {
+        str::from_utf8(slice::from_raw_parts(self.ptr, self.len)).ok()
+    }
+}
+
+/// Operations of a `FfiChain`, all implemented by the library that created it
+///
+/// Strings are only ever borrowed across the boundary: values are handed to a callback
+/// rather than returned, so that no allocation is freed by a different allocator.
+#[repr(C)]
+pub struct ChainVTable {
+    /// Call `write(out, value)` if `key` is bound, return whether it is, `false` on failure
+    pub get: unsafe extern "C" fn(
+        chain: *const c_void,
+        key: FfiStr,
+        out: *mut c_void,
+        write: unsafe extern "C" fn(out: *mut c_void, value: FfiStr),
+    ) -> bool,
+    /// Bind `key` in the toplevel, return `false` if the binding was refused
+    pub insert: unsafe extern "C" fn(chain: *mut c_void, key: FfiStr, value: FfiStr) -> bool,
+    /// Create a new chain extending this one, null on failure
+    pub extend: unsafe extern "C" fn(chain: *const c_void) -> *mut c_void,
+    pub drop: unsafe extern "C" fn(chain: *mut c_void),
+}
+
+/// Opaque owning handle to a `ChainMap<String, String>` with a stable layout
+///
+/// A host creates it with `FfiChain::new` and passes it to plugins, which only go through
+/// the vtable: they need not be built with the same compiler or the same version of this crate.
+#[repr(C)]
+pub struct FfiChain {
+    chain: *mut c_void,
+    vtable: &'static ChainVTable,
+}
+
+static VTABLE: ChainVTable = ChainVTable {
+    get: vt_get,
+    insert: vt_insert,
+    extend: vt_extend,
+    drop: vt_drop,
+};
+
+unsafe extern "C" fn vt_get(
+    chain: *const c_void,
+    key: FfiStr,
+    out: *mut c_void,
+    write: unsafe extern "C" fn(*mut c_void, FfiStr),
+) -> bool {
+    let chain = &*(chain as *const ChainMap<String, String>);
+    // A panicking `Clone` or `Hash` must not unwind into the host
+    let found = catch_unwind(AssertUnwindSafe(|| {
+        key.as_str().and_then(|key| chain.get_str(key))
+    }))
+    .unwrap_or(None);
+    match found {
+        Some(value) => {
+            write(out, FfiStr::new(&value));
+            true
+        }
+        None => false,
+    }
+}
+
+unsafe extern "C" fn vt_insert(chain: *mut c_void, key: FfiStr, value: FfiStr) -> bool {
+    let chain = &mut *(chain as *mut ChainMap<String, String>);
+    let (key, value) = match (key.as_str(), value.as_str()) {
+        (Some(key), Some(value)) => (key, value),
+        _ => return false,
+    };
+    // Unwinding out of an `extern "C"` function would abort the host
+    catch_unwind(AssertUnwindSafe(|| {
+        chain.try_insert(key.to_owned(), value.to_owned()).is_ok()
+    }))
+    .unwrap_or(false)
+}
+
+unsafe extern "C" fn vt_extend(chain: *const c_void) -> *mut c_void {
+    let chain = &*(chain as *const ChainMap<String, String>);
+    match catch_unwind(AssertUnwindSafe(|| chain.extend())) {
+        Ok(extended) => Box::into_raw(Box::new(extended)) as *mut c_void,
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn vt_drop(chain: *mut c_void) {
+    let chain = Box::from_raw(chain as *mut ChainMap<String, String>);
+    // Dropping the last handle on a layer runs its `on_drop` hook
+    let _ = catch_unwind(AssertUnwindSafe(|| drop(chain)));
+}
+
+unsafe extern "C" fn write_string(out: *mut c_void, value: FfiStr) {
+    if let Some(value) = value.as_str() {
+        *(out as *mut Option<String>) = Some(value.to_owned());
+    }
+}
+
+impl FfiChain {
+    pub fn new(chain: ChainMap<String, String>) -> Self {
+        Self {
+            chain: Box::into_raw(Box::new(chain)) as *mut c_void,
+            vtable: &VTABLE,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut out: Option<String> = None;
+        let out_ptr = &mut out as *mut Option<String> as *mut c_void;
+        unsafe { (self.vtable.get)(self.chain, FfiStr::new(key), out_ptr, write_string) };
+        out
+    }
+
+    /// Bind `key` in the toplevel, return `false` if the toplevel is locked or the quota is exceeded
+    pub fn insert(&mut self, key: &str, value: &str) -> bool {
+        unsafe { (self.vtable.insert)(self.chain, FfiStr::new(key), FfiStr::new(value)) }
+    }
+
+    /// # Panics
+    /// Panics if the library that created the chain failed to extend it
+    pub fn extend(&self) -> Self {
+        let chain = unsafe { (self.vtable.extend)(self.chain) };
+        if chain.is_null() {
+            panic!("Chain could not be extended across the boundary");
+        }
+        Self {
+            chain,
+            vtable: self.vtable,
+        }
+    }
+}
+
+impl Drop for FfiChain {
+    fn drop(&mut self) {
+        unsafe { (self.vtable.drop)(self.chain) }
+    }
+}
@@ -0,0 +1,75 @@
+//! Immutable, flattened form of a chain for the read-only part of its lifecycle
+
+use std::collections::hash_map::{self, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::ChainMap;
+
+/// Read-only snapshot of a chain, produced by `ChainMap::finish`
+///
+/// All layers are flattened into a single map, so lookups take no lock and
+/// hash the key only once. A `FrozenChain` is `Sync` whenever `K` and `V` are,
+/// and can be shared across threads through the `Arc` that `finish` returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrozenChain<K, V>
+where
+    K: Eq + Hash,
+{
+    map: HashMap<K, V>,
+}
+
+impl<K, V> FrozenChain<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Value associated with `key`, borrowed rather than cloned
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.get(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, K, V> {
+        self.map.iter()
+    }
+
+    /// Flattened bindings, as `ChainMap::collect` would have returned them
+    pub fn as_map(&self) -> &HashMap<K, V> {
+        &self.map
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Convert a fully built chain into an immutable structure optimized for reads
+    ///
+    /// Meant for the build-once, read-everywhere lifecycle of preludes and routing tables:
+    /// the result no longer sees modifications made through other handles on the chain.
+    pub fn finish(self) -> Arc<FrozenChain<K, V>> {
+        Arc::new(FrozenChain {
+            map: self.collect(),
+        })
+    }
+}
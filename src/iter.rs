@@ -0,0 +1,224 @@
+//! Iteration over the bindings of a chain
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::vec;
+
+use crate::{ChainMap, LayerId, Link, Node, Ordering, Recover};
+
+/// Copy of a layer along with its properties, see `ChainMap::iter_layers`
+#[derive(Debug, Clone)]
+pub struct LayerSnapshot<K, V> {
+    pub id: LayerId,
+    /// Distance from the top of the chain, the toplevel has depth `0`
+    pub depth: usize,
+    pub fallthrough: bool,
+    pub locked: bool,
+    pub readonly: bool,
+    pub bindings: HashMap<K, V>,
+}
+
+/// Iterator over the accessible bindings of a chain, see `ChainMap::iter`
+///
+/// Only the keys of the layer being visited are copied: values are cloned one at a time,
+/// as they are yielded, and layers are only visited once the previous ones are exhausted.
+pub struct Iter<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    next: Option<&'a Node<K, V>>,
+    layer: Option<&'a Node<K, V>>,
+    keys: vec::IntoIter<K>,
+    seen: HashSet<K>,
+    /// Stop after the first layer that is not fallthrough, see `ChainMap::local_iter`
+    local: bool,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(layer) = self.layer {
+                for key in self.keys.by_ref() {
+                    // The binding may have been removed since the keys were copied
+                    if let Some(val) = layer.elem.lock_recover().get(&key) {
+                        return Some((key, val.clone()));
+                    }
+                }
+            }
+            let node = self.next.take()?;
+            let seen = &mut self.seen;
+            self.keys = node
+                .elem
+                .lock_recover()
+                .keys()
+                .filter(|k| seen.insert((*k).clone()))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into_iter();
+            node.hidden_keys(&mut self.seen);
+            self.layer = Some(node);
+            if node.fallthrough || !self.local {
+                self.next = node.next.as_deref();
+            }
+        }
+    }
+}
+
+/// Owned iterator over the accessible bindings of a chain, see `ChainMap::iter_resumable`
+///
+/// Each layer is copied at the moment the iteration reaches it, and never consulted again.
+/// Modifications made concurrently to a layer are thus visible if they happen before the
+/// iteration reaches it, and invisible afterwards; in any case each key is yielded at most once,
+/// with the value from the first layer that bound it when that layer was reached.
+pub struct ResumableIter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    next: Link<K, V>,
+    current: vec::IntoIter<(K, V)>,
+    seen: HashSet<K>,
+}
+
+impl<K, V> ResumableIter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Whether all layers have been visited and all bindings yielded
+    pub fn is_finished(&self) -> bool {
+        self.next.is_none() && self.current.len() == 0
+    }
+}
+
+impl<K, V> Iterator for ResumableIter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(binding) = self.current.next() {
+                return Some(binding);
+            }
+            let node = self.next.take()?;
+            let seen = &mut self.seen;
+            self.current = node
+                .elem
+                .lock_recover()
+                .iter()
+                .filter(|(k, _)| seen.insert((*k).clone()))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+                .into_iter();
+            node.hidden_keys(&mut self.seen);
+            self.next = node.next.clone();
+        }
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Iterate over all accessible bindings, in arbitrary order
+    ///
+    /// Each key is yielded once, with the same value that `get` would return. Unlike `collect`,
+    /// nothing is gathered in advance: stopping the iteration early avoids cloning the bindings
+    /// of the layers that were not reached.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            next: Some(self.link()),
+            layer: None,
+            keys: Vec::new().into_iter(),
+            seen: HashSet::new(),
+            local: false,
+        }
+    }
+
+    /// Same as `iter`, restricted to the bindings visible to `local_get`
+    ///
+    /// These are the bindings of the handle's own scope, as created by `fork`.
+    pub fn local_iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            local: true,
+            ..self.iter()
+        }
+    }
+
+    /// Iterate over the layers of the chain, from the toplevel down to the root
+    ///
+    /// Each layer is copied when the iteration reaches it, shadowed bindings included.
+    /// The empty layer left pending by `fork` is only listed once it is created, but is
+    /// still counted in the depths.
+    pub fn iter_layers(&self) -> impl Iterator<Item = LayerSnapshot<K, V>> + '_ {
+        let mut r = Some(self.link());
+        let mut depth = self.link_depth();
+        std::iter::from_fn(move || {
+            let m = r?;
+            let snapshot = LayerSnapshot {
+                id: m.id,
+                depth,
+                fallthrough: m.fallthrough,
+                locked: !m.unlocked.load(Ordering::Relaxed),
+                readonly: !m.write_auth.load(Ordering::Relaxed),
+                bindings: m.elem.lock_recover().clone(),
+            };
+            depth += 1;
+            r = m.next.as_ref();
+            Some(snapshot)
+        })
+    }
+
+    /// Iterate over all accessible bindings without borrowing the handle
+    ///
+    /// The returned iterator keeps the layers it has yet to visit alive, so that a very
+    /// large iteration can be paused (by simply keeping the iterator around) and resumed
+    /// later, even if the chain is modified in between. See `ResumableIter` for the exact
+    /// guarantees in presence of concurrent modifications.
+    pub fn iter_resumable(&self) -> ResumableIter<K, V> {
+        ResumableIter {
+            next: Some(self.link().clone()),
+            current: Vec::new().into_iter(),
+            seen: HashSet::new(),
+        }
+    }
+}
+
+impl<K, V> IntoIterator for ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+    type IntoIter = ResumableIter<K, V>;
+
+    /// Same as `iter_resumable`
+    fn into_iter(self) -> ResumableIter<K, V> {
+        self.iter_resumable()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+    type IntoIter = Iter<'a, K, V>;
+
+    /// Same as `iter`
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
@@ -0,0 +1,95 @@
+//! Conversions between chains and JSON documents
+
+use std::collections::HashSet;
+
+use serde::de::{DeserializeOwned, Error};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{ChainMap, Recover};
+
+impl<V> ChainMap<String, V>
+where
+    V: Clone + DeserializeOwned,
+{
+    /// Build a chain from a JSON array of objects, lowest precedence first
+    ///
+    /// The first object becomes the root and each following object extends the previous one,
+    /// so that the last object is the toplevel of the returned handle.
+    /// An empty array produces an empty root.
+    pub fn from_json_layers(value: Value) -> Result<Self, serde_json::Error> {
+        let layers = match value {
+            Value::Array(layers) => layers,
+            _ => return Err(serde_json::Error::custom("expected an array of layers")),
+        };
+        let mut chain: Option<Self> = None;
+        for layer in layers {
+            if !layer.is_object() {
+                return Err(serde_json::Error::custom("expected each layer to be an object"));
+            }
+            let map = serde_json::from_value(layer)?;
+            chain = Some(match chain {
+                None => ChainMap::new_with(map),
+                Some(chain) => chain.extend_with(map),
+            });
+        }
+        Ok(chain.unwrap_or_default())
+    }
+}
+
+impl<V> ChainMap<String, V>
+where
+    V: Clone + Serialize,
+{
+    /// Inverse of `from_json_layers`: one object per layer, lowest precedence first
+    ///
+    /// If `compact` is set, bindings shadowed by a higher layer or hidden by a tombstone are left out.
+    /// Since they are unreachable from this handle, the resulting chain resolves every key the same way,
+    /// but it can be much smaller when the same keys are bound again and again.
+    /// Tombstones themselves are not represented: without `compact`, hidden bindings are kept.
+    pub fn to_json_layers(&self, compact: bool) -> Result<Value, serde_json::Error> {
+        let mut seen = HashSet::new();
+        let mut layers = Vec::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            let mut layer = Map::new();
+            for (k, v) in m.elem.lock_recover().iter() {
+                if seen.insert(k.clone()) || !compact {
+                    layer.insert(k.clone(), serde_json::to_value(v)?);
+                }
+            }
+            if compact {
+                m.hidden_keys(&mut seen);
+            }
+            layers.push(Value::Object(layer));
+            r = m.next.as_ref();
+        }
+        layers.reverse();
+        Ok(Value::Array(layers))
+    }
+
+    /// Describe every accessible binding along with the layer that supplies it
+    ///
+    /// Produces an object mapping each key to `{"value": ..., "depth": ...}`,
+    /// where `depth` is the distance from the toplevel, as in `ResolutionTrace`.
+    pub fn to_json_with_provenance(&self) -> Result<Value, serde_json::Error> {
+        let mut seen = HashSet::new();
+        let mut out = Map::new();
+        let mut r = Some(self.link());
+        let mut depth = 0;
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if seen.insert(k.clone()) {
+                    let mut binding = Map::new();
+                    binding.insert(String::from("value"), serde_json::to_value(v)?);
+                    binding.insert(String::from("depth"), Value::from(depth));
+                    out.insert(k.clone(), Value::Object(binding));
+                }
+            }
+            m.hidden_keys(&mut seen);
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        Ok(Value::Object(out))
+    }
+}
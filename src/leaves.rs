@@ -0,0 +1,155 @@
+//! Private scratch layers for the worker threads of a shared chain
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+use std::thread::{self, ThreadId};
+
+use crate::{Mutex, Recover, SyncChainMap};
+
+/// A shared chain along with one leaf layer per thread, see `SyncChainMap::thread_local_leaf`
+///
+/// Each thread that goes through it gets its own layer on top of the shared chain, created
+/// on first use: bindings made by a worker are only visible to that worker, while bindings
+/// of the shared chain are visible to all of them. Share it between threads with an `Arc`.
+/// The leaf of a thread is dropped when the thread exits.
+pub struct ThreadLeaves<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    parent: SyncChainMap<K, V>,
+    leaves: Arc<Leaves<K, V>>,
+}
+
+type Leaves<K, V> = Mutex<HashMap<ThreadId, SyncChainMap<K, V>>>;
+
+/// Leaves of any key and value types, from which a thread can remove its own
+trait Reclaim {
+    fn reclaim(&self, thread: ThreadId);
+}
+
+impl<K, V> Reclaim for Leaves<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn reclaim(&self, thread: ThreadId) {
+        self.lock_recover().remove(&thread);
+    }
+}
+
+/// Every `ThreadLeaves` in which the current thread has a leaf, dropped when the thread exits
+struct ThreadExit {
+    thread: ThreadId,
+    owners: Vec<Weak<dyn Reclaim>>,
+}
+
+impl Drop for ThreadExit {
+    fn drop(&mut self) {
+        for owner in self.owners.drain(..) {
+            if let Some(owner) = owner.upgrade() {
+                owner.reclaim(self.thread);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static EXIT: RefCell<ThreadExit> = RefCell::new(ThreadExit {
+        thread: thread::current().id(),
+        owners: Vec::new(),
+    });
+}
+
+impl<K, V> ThreadLeaves<K, V>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    /// Handle on the leaf of the current thread, created if it does not exist yet
+    pub fn leaf(&self) -> SyncChainMap<K, V> {
+        let mut leaves = self.leaves.lock_recover();
+        if let Some(leaf) = leaves.get(&thread::current().id()) {
+            return leaf.clone_linked();
+        }
+        let leaf = self.parent.extend();
+        leaves.insert(thread::current().id(), leaf.clone_linked());
+        drop(leaves);
+        self.reclaim_on_exit();
+        leaf
+    }
+
+    /// Make sure that the leaf of the current thread is removed when the thread exits
+    fn reclaim_on_exit(&self) {
+        let owner: Weak<dyn Reclaim> = Arc::downgrade(&self.leaves) as Weak<Leaves<K, V>>;
+        // Fails if the thread is already exiting, the leaf then stays until `clear`
+        let _ = EXIT.try_with(|exit| {
+            let mut exit = exit.borrow_mut();
+            exit.owners.retain(|other| other.strong_count() > 0);
+            if !exit.owners.iter().any(|other| other.ptr_eq(&owner)) {
+                exit.owners.push(owner);
+            }
+        });
+    }
+
+    /// Handle on the shared chain below the leaves
+    pub fn parent(&self) -> SyncChainMap<K, V> {
+        self.parent.clone_linked()
+    }
+
+    /// Retrieve a value as seen by the current thread
+    ///
+    /// Does not create a leaf: a thread that never inserted only sees the shared chain.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let leaf = self
+            .leaves
+            .lock_recover()
+            .get(&thread::current().id())
+            .map(SyncChainMap::clone_linked);
+        match leaf {
+            Some(leaf) => leaf.get(key),
+            None => self.parent.get(key),
+        }
+    }
+
+    /// Create a binding in the leaf of the current thread
+    pub fn insert(&self, key: K, val: V) -> Option<V> {
+        self.leaf().insert(key, val)
+    }
+
+    /// Number of threads that currently have a leaf
+    pub fn threads(&self) -> usize {
+        self.leaves.lock_recover().len()
+    }
+
+    /// Discard the leaf of the current thread and all its bindings
+    ///
+    /// This happens anyway when the thread exits, a thread that outlives its task can
+    /// call this to start over with an empty leaf.
+    pub fn clear(&self) -> bool {
+        self.leaves
+            .lock_recover()
+            .remove(&thread::current().id())
+            .is_some()
+    }
+}
+
+impl<K, V> SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    /// Give every thread a private layer on top of this chain, without explicit forks
+    pub fn thread_local_leaf(&self) -> ThreadLeaves<K, V> {
+        ThreadLeaves {
+            parent: self.clone_linked(),
+            leaves: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
@@ -1,10 +1,115 @@
 //#![doc(html_playground_url = "https://play.rust-lang.org/")]
 
-use std::collections::HashMap;
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
 use std::rc::Rc;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use cache::ReadCache;
+use memo::Memo;
+use quota::QuotaScope;
+use routing::Router;
+use trace::Lookup;
+
+#[cfg(not(loom))]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+use std::sync::{Mutex, MutexGuard};
+
+// Under `--cfg loom` the synchronization primitives are swapped for loom's
+// so that the locking protocol can be model-checked.
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::sync::{Mutex, MutexGuard};
+
+/// Locking that survives a panic in another user of the chain
+///
+/// A layer is only ever left inconsistent by a panic in user code while it is locked,
+/// e.g. in the closure passed to `update_with`. Rather than turning such a panic into
+/// a panic of every later operation, the data is used as is. Poisoned layers can still
+/// be detected with `validate`.
+trait Recover<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> Recover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+mod batch;
+mod bloom;
+mod bulk;
+mod cache;
+mod clock;
+mod compat;
+mod debug;
+mod entry;
+mod error;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod frozen;
+mod iter;
+#[cfg(feature = "sync")]
+mod leaves;
+mod local;
+mod memo;
+#[cfg(feature = "sync")]
+mod mirror;
+#[cfg(feature = "serde_json")]
+mod json;
+mod normalize;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "arc-swap")]
+mod published;
+mod quota;
+mod routing;
+mod shared;
+mod snapshot;
+#[cfg(feature = "sync")]
+mod sync;
+pub mod testing;
+mod trace;
+mod typed;
+mod validate;
+mod vecmap;
+mod view;
+
+pub use bulk::{BulkOptions, BulkStats};
+pub use cache::CacheStats;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use compat::{FlatAdapter, MapEntry, MapLike};
+pub use debug::{DebugScope, DebugVariable, ScopeKind};
+pub use entry::{Entry, ValueGuard, ValueGuardMut};
+pub use error::ChainMapError;
+#[cfg(feature = "ffi")]
+pub use ffi::{ChainVTable, FfiChain, FfiStr};
+pub use frozen::FrozenChain;
+pub use iter::{Iter, LayerSnapshot, ResumableIter};
+#[cfg(feature = "sync")]
+pub use leaves::ThreadLeaves;
+pub use local::LocalChainMap;
+#[cfg(feature = "sync")]
+pub use mirror::{Mirror, Subscription};
+pub use normalize::{KeyNormalizer, NormalizedChainMap};
+pub use quota::{Quota, QuotaExceeded};
+pub use routing::RoutingStats;
+pub use shared::SharedChainMap;
+pub use snapshot::{Snapshot, Snapshotter};
+#[cfg(feature = "sync")]
+pub use sync::SyncChainMap;
+pub use trace::{Outcome, ResolutionTrace, TraceStep};
+pub use typed::TypedKey;
+pub use validate::Issue;
+pub use vecmap::ChainVecMap;
+pub use view::{DerivedView, RemappedView};
+
+#[cfg(feature = "derive")]
+pub use chainmap_derive::ChainBacked;
 
 /// A structure for managing a tree of `HashMap`s
 ///
@@ -13,94 +118,716 @@ use std::sync::atomic::{AtomicBool, Ordering};
 /// adapted and extended with `Mutex`es and `HashMap`s
 pub struct ChainMap<K, V>
 where
-    K: Eq + Hash + Clone,
-    V: Clone,
+    K: Eq + Hash,
 {
-    head: Link<K, V>,
+    head: Rc<Node<K, V>>,
+    /// Set by `fork`: `head` is then shared with the new branch, and this handle needs
+    /// a fallthrough layer of its own above it before its toplevel can be modified.
+    /// That layer is only created once it is actually needed.
+    overlay: Option<OnceLock<Rc<Node<K, V>>>>,
+    flat: Mutex<Option<FlatCache<K, V>>>,
+    quota: Option<QuotaScope<K, V>>,
+    routing: Option<Router<K>>,
+    memo: Option<Memo<K, V>>,
+}
+
+/// Result of a previous `collect_cached` or view computation, valid as long as the generations match
+struct FlatCache<K, V> {
+    generations: Vec<usize>,
+    map: Arc<HashMap<K, V>>,
 }
 
+/// Where a key is resolved, relative to the scope of a `ChainMap`
+///
+/// A scope is a group of layers linked by fallthrough, as created by `fork`.
+/// Scopes are numbered from the top: the scope of the handle has depth `0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    /// Visible through `local_get`
+    Local,
+    /// Bound in an enclosing scope other than the root
+    Captured { depth: usize },
+    /// Bound in the root layer, and not in any scope above it
+    Global,
+    /// Not bound anywhere in the chain
+    Unbound,
+}
+
+/// What `ChainMap::update_or_report` did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The existing binding was modified in place
+    Updated,
+    /// The first layer that binds the key is locked, a new binding shadows it in the toplevel
+    ShadowedDueToLock,
+    /// The key is bound behind a write-protected layer, a new binding shadows it in the toplevel
+    ShadowedDueToReadonly,
+    /// The key was not bound anywhere, a new binding was created in the toplevel
+    InsertedFresh,
+}
+
+/// Identifies a layer for as long as it exists, see `ChainMap::layer_ids`
+///
+/// Identifiers are never reused, even after the layer is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LayerId(usize);
+
+/// Properties of the layer that owns a binding, as reported by `ChainMap::flags_for`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyFlags {
+    /// Distance from the top of the chain, the toplevel has depth `0`
+    pub depth: usize,
+    pub locked: bool,
+    pub readonly: bool,
+    pub fallthrough: bool,
+}
+
+/// A lookup reached its depth limit before resolving the key, see `ChainMap::get_within`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthExceeded {
+    /// Depth of the deepest layer that was probed
+    pub max_depth: usize,
+}
+
+impl fmt::Display for DepthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lookup exceeded maximum depth {}", self.max_depth)
+    }
+}
+
+impl std::error::Error for DepthExceeded {}
+
+/// A key along with the differing values it is bound to, see `ChainMap::collect_checked`
+pub type Conflict<K, V> = (K, Vec<V>);
+
 type Link<K, V> = Option<Rc<Node<K, V>>>;
 
 struct Node<K, V>
 where
-    K: Eq + Hash + Clone,
-    V: Clone,
+    K: Eq + Hash,
 {
     elem: Mutex<HashMap<K, V>>,
     next: Link<K, V>,
     fallthrough: bool,
     unlocked: AtomicBool,
     write_auth: AtomicBool,
+    write_filter: Mutex<Option<WriteFilter<K>>>,
+    generation: AtomicUsize,
+    pool: Mutex<Option<HashSet<V>>>,
+    history: Mutex<Option<History<K, V>>>,
+    cache: Option<Mutex<ReadCache<K, V>>>,
+    /// Only meaningful for the root, see `ChainMap::set_write_up_promotion`
+    promote: AtomicBool,
+    id: LayerId,
+    /// Set if the layer is known to be empty, so that lookups can skip locking it.
+    /// Only ever set at creation, and cleared by `touch`.
+    empty: AtomicBool,
+    on_drop: Mutex<Option<DropHook<K, V>>>,
+    /// Keys hidden by this layer, see `ChainMap::hide`
+    tombstones: Mutex<HashSet<K>>,
+    /// Set once `tombstones` is first added to, so that lookups can skip locking it
+    hiding: AtomicBool,
+    /// Set for chains with Bloom filters, see `ChainMap::bloom_filtered`
+    bloom: OnceLock<bloom::Bloom>,
+    /// Set for read-mostly chains, see `ChainMap::read_mostly`
+    #[cfg(feature = "arc-swap")]
+    published: OnceLock<arc_swap::ArcSwap<published::Published<K, V>>>,
+}
+
+/// Source of generation numbers, shared by all layers of all chains
+///
+/// Since every modification draws a fresh number, two layers can never be observed
+/// with the same generation: comparing generations is enough to detect any change
+/// in a chain, including its structure.
+static GENERATION: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+fn next_generation() -> usize {
+    GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Predicate deciding which keys may be updated through a layer
+type WriteFilter<K> = Arc<dyn Fn(&K) -> bool + Send + Sync>;
+
+/// Previous versions of the values of an append-only layer, see `ChainMap::extend_log`
+#[derive(Clone)]
+struct History<K, V> {
+    versions: HashMap<K, Vec<V>>,
+    /// Set by `extend_log`, so that values can be archived without requiring `V: Clone`
+    copy: fn(&V) -> V,
+    /// Same as `copy`, for keys
+    copy_key: fn(&K) -> K,
+}
+
+/// Receives the bindings of a layer when it is dropped, see `ChainMap::on_drop`
+type DropHook<K, V> = Box<dyn FnOnce(HashMap<K, V>)>;
+
+impl<K, V> Node<K, V>
+where
+    K: Eq + Hash,
+{
+    fn new(elem: HashMap<K, V>, next: Link<K, V>, fallthrough: bool) -> Self {
+        let node = Self {
+            id: LayerId(next_generation()),
+            empty: AtomicBool::new(elem.is_empty()),
+            elem: Mutex::new(elem),
+            next,
+            fallthrough,
+            unlocked: AtomicBool::new(true),
+            write_auth: AtomicBool::new(true),
+            write_filter: Mutex::new(None),
+            generation: AtomicUsize::new(next_generation()),
+            pool: Mutex::new(None),
+            history: Mutex::new(None),
+            cache: None,
+            promote: AtomicBool::new(false),
+            on_drop: Mutex::new(None),
+            tombstones: Mutex::new(HashSet::new()),
+            hiding: AtomicBool::new(false),
+            bloom: OnceLock::new(),
+            #[cfg(feature = "arc-swap")]
+            published: OnceLock::new(),
+        };
+        if node.next.as_ref().is_some_and(|next| next.has_bloom()) {
+            node.enable_bloom();
+        }
+        #[cfg(feature = "arc-swap")]
+        if let Some(copy_key) = node.next.as_ref().and_then(|next| next.published_copier()) {
+            node.publish(copy_key);
+        }
+        node
+    }
+
+    /// Record a modification of this layer
+    fn touch(&self) {
+        self.generation.store(next_generation(), Ordering::Relaxed);
+        self.empty.store(false, Ordering::Relaxed);
+    }
+
+    /// Value bound to `key` in this layer, without locking it if it is known not to bind it
+    fn lookup<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        if self.empty.load(Ordering::Relaxed) || !self.may_contain(key) {
+            return None;
+        }
+        #[cfg(feature = "arc-swap")]
+        if let Some(published) = self.published() {
+            return published.map().get(key).cloned();
+        }
+        self.elem.lock_recover().get(key).cloned()
+    }
+
+    /// Call `f` with the stored form of `key`, whether it is bound in this layer or only cached
+    fn with_stored_key<Q, F>(&self, key: &Q, f: F)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        F: FnOnce(&K),
+    {
+        if let Some((k, _)) = self.elem.lock_recover().get_key_value(key) {
+            return f(k);
+        }
+        if let Some(cache) = &self.cache {
+            if let Some((k, _)) = cache.lock_recover().entries.get_key_value(key) {
+                f(k);
+            }
+        }
+    }
+
+    /// Whether this layer has a tombstone for `key`, which should then not be looked up further
+    fn hides<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.hiding.load(Ordering::Relaxed) && self.tombstones.lock_recover().contains(key)
+    }
+
+    /// Keys hidden by this layer, only locks the tombstones if there can be any
+    fn hidden_keys(&self, out: &mut HashSet<K>)
+    where
+        K: Clone,
+    {
+        if self.hiding.load(Ordering::Relaxed) {
+            out.extend(self.tombstones.lock_recover().iter().cloned());
+        }
+    }
+
+    /// Same as `archive`, but only copy `old` if it is actually kept
+    fn archive_copy(&self, key: &K, old: &V) {
+        if let Some(history) = &mut *self.history.lock_recover() {
+            let old = (history.copy)(old);
+            history.push(key, old);
+        }
+    }
+
+    /// Keep a superseded value, if this is an append-only layer
+    fn archive(&self, key: &K, old: V) {
+        if let Some(history) = &mut *self.history.lock_recover() {
+            history.push(key, old);
+        }
+    }
+
+    /// Same as `archive`, for a key given in borrowed form
+    ///
+    /// The stored form of `key` is only looked up in `layer` if `old` is actually kept.
+    fn archive_at<Q>(&self, layer: &HashMap<K, V>, key: &Q, old: V)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(history) = &mut *self.history.lock_recover() {
+            if let Some((stored, _)) = layer.get_key_value(key) {
+                history.push(stored, old);
+            }
+        }
+    }
+
+    /// Whether `key` may be updated in this layer or any layer below
+    fn can_write(&self, key: &K) -> bool {
+        self.write_auth.load(Ordering::Relaxed)
+            && match &*self.write_filter.lock_recover() {
+                Some(filter) => filter(key),
+                None => true,
+            }
+    }
+
+    /// Same as `can_write`, for a key given in borrowed form
+    ///
+    /// An owned copy of `key` is only made if the layer has a write filter.
+    fn can_write_borrowed<Q>(&self, key: &Q) -> bool
+    where
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.write_auth.load(Ordering::Relaxed)
+            && match &*self.write_filter.lock_recover() {
+                Some(filter) => filter(&key.to_owned()),
+                None => true,
+            }
+    }
+
+    /// Same as `archive_copy`, for a key given in borrowed form, see `archive_at`
+    fn archive_copy_at<Q>(&self, layer: &HashMap<K, V>, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(history) = &mut *self.history.lock_recover() {
+            if let Some((stored, old)) = layer.get_key_value(key) {
+                let old = (history.copy)(old);
+                history.push(stored, old);
+            }
+        }
+    }
+
+    fn has_write_filter(&self) -> bool {
+        self.write_filter.lock_recover().is_some()
+    }
+}
+
+impl<K, V> History<K, V>
+where
+    K: Eq + Hash,
+{
+    fn push(&mut self, key: &K, old: V) {
+        // Only copy the key the first time it is archived
+        match self.versions.get_mut(key) {
+            Some(versions) => versions.push(old),
+            None => {
+                self.versions.insert((self.copy_key)(key), vec![old]);
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for Node<K, V>
+where
+    K: Eq + Hash,
+{
+    fn drop(&mut self) {
+        let hook = match self.on_drop.lock() {
+            Ok(mut hook) => hook.take(),
+            Err(_) => None,
+        };
+        // A poisoned layer is not flushed, panicking here could abort the process
+        if let (Some(hook), Ok(mut elem)) = (hook, self.elem.lock()) {
+            hook(std::mem::take(&mut *elem));
+        }
+    }
 }
 
 impl<K, V> ChainMap<K, V>
 where
-    K: Eq + Hash + Clone,
-    V: Clone,
+    K: Eq + Hash,
 {
+    fn from_head(head: Rc<Node<K, V>>) -> Self {
+        Self {
+            head,
+            overlay: None,
+            flat: Mutex::new(None),
+            quota: None,
+            routing: None,
+            memo: None,
+        }
+    }
+
+    /// New handle with `node` as toplevel, inheriting the quota of `self`
+    fn child(&self, node: Node<K, V>) -> Self {
+        let mut child = Self::from_head(Rc::new(node));
+        child.quota = self.quota.clone();
+        child
+    }
+
+    /// Top of the chain, as seen by lookups
+    fn link(&self) -> &Rc<Node<K, V>> {
+        match &self.overlay {
+            Some(overlay) => overlay.get().unwrap_or(&self.head),
+            None => &self.head,
+        }
+    }
+
+    /// Top of the chain, creating the fallthrough layer left pending by `fork` if needed
+    fn settled_link(&self) -> &Rc<Node<K, V>> {
+        match &self.overlay {
+            Some(overlay) => overlay.get_or_init(|| {
+                Rc::new(Node::new(HashMap::new(), Some(self.head.clone()), true))
+            }),
+            None => &self.head,
+        }
+    }
+
+    /// Whether `fork` left a fallthrough layer to be created on the first modification
+    ///
+    /// Reads behave as if it already existed: it is empty, unlocked and writable, and
+    /// depths count it as the toplevel so that they do not change once it is created.
+    fn overlay_pending(&self) -> bool {
+        matches!(&self.overlay, Some(overlay) if overlay.get().is_none())
+    }
+
+    /// Depth of `link()`, see `overlay_pending`
+    fn link_depth(&self) -> usize {
+        usize::from(self.overlay_pending())
+    }
+
+    /// Toplevel layer, not shared with any branch created by `fork`
+    fn top(&self) -> &Node<K, V> {
+        self.settled_link()
+    }
+
+    /// Toplevel layer, if it is not shared with any other handle
+    fn top_mut(&mut self) -> Option<&mut Node<K, V>> {
+        self.top();
+        if let Some(overlay) = self.overlay.take() {
+            self.head = overlay.into_inner().unwrap();
+        }
+        Rc::get_mut(&mut self.head)
+    }
+
     /// Util only
     #[allow(dead_code)]
     fn tail(&self) -> Self {
-        Self {
-            head: self.head.as_ref().and_then(|node| node.next.clone()),
-        }
+        Self::from_head(self.link().next.clone().unwrap())
     }
 
     /// Util only
     #[allow(dead_code)]
-    fn head(&self) -> Option<&Mutex<HashMap<K, V>>> {
-        self.head.as_ref().map(|node| &node.elem)
+    fn head(&self) -> &Mutex<HashMap<K, V>> {
+        &self.link().elem
+    }
+
+    /// Last layer of the chain
+    fn root_node(&self) -> &Rc<Node<K, V>> {
+        let mut r = self.link();
+        while let Some(m) = &r.next {
+            r = m;
+        }
+        r
     }
 
     /// Create a new empty root
-    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(HashMap::new()),
-                next: None,
-                fallthrough: false,
-                unlocked: AtomicBool::new(true),
-                write_auth: AtomicBool::new(true),
-            })),
-        }
+        Self::from_head(Rc::new(Node::new(HashMap::new(), None, false)))
     }
 
     /// Create a new root and initialize with given map
     pub fn new_with(h: HashMap<K, V>) -> Self {
-        Self {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(h),
-                next: None,
-                fallthrough: false,
-                unlocked: AtomicBool::new(true),
-                write_auth: AtomicBool::new(true),
-            })),
-        }
+        Self::from_head(Rc::new(Node::new(h, None, false)))
+    }
+
+    /// Handle to the last layer of the chain
+    ///
+    /// Bindings made through the returned handle are visible from `self`, as for `clone_linked`.
+    pub fn root(&self) -> Self {
+        Self::from_head(Rc::clone(self.root_node()))
     }
 
     /// Create a new binding in the toplevel
+    ///
+    /// Returns the value previously bound to `key` in the toplevel, if any.
+    /// Bindings of lower layers are merely shadowed and not reported.
+    /// # Panics
+    /// - if toplevel map is locked
+    /// - if the new binding would exceed the quota, see `set_quota`
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        match self.try_insert(key, val) {
+            Ok(old) => old,
+            Err(err) => panic!("{}, could not insert", err),
+        }
+    }
+
+    /// Create a new binding in the toplevel, regardless of the quota
     /// # Panics
     /// Panics if toplevel map is locked
-    pub fn insert(&mut self, key: K, val: V) {
+    fn insert_unchecked(&mut self, key: K, val: V) -> Option<V> {
         if self.is_unlocked() {
-            self.head().unwrap().lock().unwrap().insert(key, val);
+            let node = self.top();
+            node.forget(&key);
+            let mut map = node.elem.lock_recover();
+            node.touch_key(&key);
+            match map.entry(key) {
+                hash_map::Entry::Occupied(mut entry) => {
+                    let old = entry.insert(val);
+                    node.archive_copy(entry.key(), &old);
+                    Some(old)
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert(val);
+                    None
+                }
+            }
         } else {
             panic!("Map is locked, could not insert");
         }
     }
 
+    /// Merge all bindings of `iter` into the toplevel, locking it only once
+    ///
+    /// Returns the keys that were already bound in the toplevel, and whose value was replaced.
+    /// Keys bound only lower in the chain are merely shadowed and not reported.
+    /// # Panics
+    /// - if toplevel map is locked
+    /// - if the new bindings would exceed the quota, see `set_quota`
+    pub fn insert_all<I>(&mut self, iter: I) -> Vec<K>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        if self.is_locked() {
+            panic!("Map is locked, could not insert");
+        }
+        if self.quota.is_none() {
+            return self.insert_all_unchecked(iter);
+        }
+        // The number of new keys has to be known before anything is inserted
+        let bindings = iter.into_iter().collect::<Vec<_>>();
+        let new = {
+            let layer = self.top().elem.lock_recover();
+            bindings
+                .iter()
+                .map(|(k, _)| k)
+                .filter(|k| !layer.contains_key(k))
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        if let Err(err) = self.check_quota(new) {
+            panic!("{}, could not insert", err);
+        }
+        self.insert_all_unchecked(bindings)
+    }
+
+    fn insert_all_unchecked<I>(&mut self, iter: I) -> Vec<K>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let node = self.top();
+        let mut layer = node.elem.lock_recover();
+        let mut overwritten = Vec::new();
+        for (key, val) in iter {
+            node.forget(&key);
+            // Hand back the stored key, the new one takes its place
+            if let Some((stored, old)) = layer.remove_entry(&key) {
+                node.archive(&stored, old);
+                overwritten.push(stored);
+            }
+            layer.insert(key, val);
+        }
+        node.touch();
+        overwritten
+    }
+
+    /// Remove the binding of `key` from the toplevel, returning its value
+    ///
+    /// Bindings of `key` lower in the chain become visible again, see `hide` to mask them as well.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.is_locked() {
+            panic!("Map is locked, could not remove");
+        }
+        let node = self.top();
+        let old = node.elem.lock_recover().remove(key);
+        if old.is_some() {
+            node.touch_key(key);
+        }
+        old
+    }
+
+    /// Take the binding of `key` out of the toplevel, so that lookups fall back to the value it shadowed
+    ///
+    /// Same as `remove`, under the name used when scopes are treated as a stack.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn pop<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove(key)
+    }
+
+    /// Remove all bindings from the toplevel and iterate over them
+    ///
+    /// Bindings are moved out rather than cloned. Tombstones of the toplevel are kept.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn drain(&mut self) -> std::collections::hash_map::IntoIter<K, V> {
+        if self.is_locked() {
+            panic!("Map is locked, could not drain");
+        }
+        let node = self.top();
+        let map = std::mem::take(&mut *node.elem.lock_recover());
+        node.touch();
+        map.into_iter()
+    }
+
+    /// Remove the binding of `key` from the toplevel, and mask the bindings of lower layers
+    ///
+    /// Lookups going through the toplevel then behave as if `key` was not bound anywhere below,
+    /// but the lower layers are left untouched and other handles still see their bindings.
+    /// A new binding of `key` in the toplevel takes precedence over the tombstone,
+    /// which stays in place until `unhide` is called.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn hide(&mut self, key: K) -> Option<V> {
+        if self.is_locked() {
+            panic!("Map is locked, could not hide");
+        }
+        let node = self.top();
+        let old = node.elem.lock_recover().remove(&key);
+        node.tombstones.lock_recover().insert(key);
+        node.hiding.store(true, Ordering::Relaxed);
+        node.touch();
+        old
+    }
+
+    /// Remove the tombstone placed by `hide` on `key` in the toplevel, if any
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn unhide<Q>(&mut self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if self.is_locked() {
+            panic!("Map is locked, could not unhide");
+        }
+        let node = self.top();
+        let removed = node.tombstones.lock_recover().remove(key);
+        if removed {
+            node.touch();
+        }
+        removed
+    }
+
+    /// View of `key` in the toplevel, for in-place manipulation
+    ///
+    /// Only the toplevel is considered: an entry is vacant even if `key` is bound lower in the chain.
+    /// The toplevel stays locked for as long as the entry or the value it yields are alive.
+    /// # Panics
+    /// Panics if toplevel map is locked.
+    /// Binding a vacant entry panics if it would exceed the quota, see `set_quota`.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V>
+    where
+        V: Clone,
+        K: Clone,
+    {
+        if self.is_locked() {
+            panic!("Map is locked, could not create entry");
+        }
+        // Checked before locking the toplevel, which the quota also counts
+        let full = self.check_quota(1).err();
+        // The entry grants mutable access, we have to assume it will be used
+        let node = self.top();
+        node.touch();
+        Entry {
+            layer: node.elem.lock_recover(),
+            node,
+            key,
+            full,
+        }
+    }
+
+    /// Only keep bindings of the toplevel for which `f` returns `true`
+    ///
+    /// Bindings that `update` may not modify, because the toplevel is `readonly` or
+    /// because of its write filter, are kept without being passed to `f`.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        if self.is_unlocked() {
+            let node = self.top();
+            let writable = node.write_auth.load(Ordering::Relaxed);
+            let filter = node.write_filter.lock_recover().clone();
+            node.elem.lock_recover().retain(|k, v| {
+                let allowed = writable
+                    && match &filter {
+                        Some(filter) => filter(k),
+                        None => true,
+                    };
+                !allowed || f(k, v)
+            });
+            node.touch();
+        } else {
+            panic!("Map is locked, could not retain");
+        }
+    }
+
+    /// Remove all bindings and tombstones of the toplevel, leaving the layers below intact
+    ///
+    /// Allows a scope to be reused without creating a new layer.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn clear_local(&mut self) {
+        if self.is_unlocked() {
+            let node = self.top();
+            node.elem.lock_recover().clear();
+            node.tombstones.lock_recover().clear();
+            node.touch();
+        } else {
+            panic!("Map is locked, could not clear");
+        }
+    }
+
     /// Protect map against modifications
     ///
     /// Does not extend to maps below, all keys whose value must not change should be re-inserted
     /// in the toplevel.
     pub fn lock(&mut self) {
-        self.head.as_ref().unwrap().unlocked.store(false, Ordering::Relaxed);
+        self.top().unlocked.store(false, Ordering::Relaxed);
     }
 
     /// Release write protection
     pub fn unlock(&mut self) {
-        self.head.as_ref().unwrap().unlocked.store(true, Ordering::Relaxed);
+        self.top().unlocked.store(true, Ordering::Relaxed);
     }
 
     pub fn locked(mut self) -> Self {
@@ -114,124 +841,961 @@ where
     }
 
     pub fn is_unlocked(&self) -> bool {
-        self.head.as_ref().unwrap().unlocked.load(Ordering::Relaxed)
+        self.overlay_pending() || self.link().unlocked.load(Ordering::Relaxed)
     }
 
     pub fn is_locked(&self) -> bool {
-        !self.head.as_ref().unwrap().unlocked.load(Ordering::Relaxed)
+        !self.is_unlocked()
     }
 
     /// Resulting layer cannot modify any value lower in the map
     pub fn readonly(self) -> Self {
-        self.head.as_ref().unwrap().write_auth.store(false, Ordering::Relaxed);
+        self.top().write_auth.store(false, Ordering::Relaxed);
         self
     }
 
+    /// Whether `local_get` continues to the next layer when the toplevel lacks a key
+    pub fn is_fallthrough(&self) -> bool {
+        self.overlay_pending() || self.link().fallthrough
+    }
+
+    /// Change whether `local_get` continues to the next layer when the toplevel lacks a key
+    /// # Panics
+    /// Panics if the toplevel is shared, i.e. if it has been extended or cloned into other handles
+    pub fn set_fallthrough(&mut self, fallthrough: bool) {
+        match self.top_mut() {
+            Some(node) => node.fallthrough = fallthrough,
+            None => panic!("Toplevel is shared, could not set fallthrough"),
+        }
+    }
+
+    /// Restrict which keys can be updated through this layer
+    ///
+    /// `update` and `update_or` going through this layer may only modify keys for which
+    /// `filter` returns `true`, whether they are bound here or lower in the chain.
+    /// `readonly` is the special case of a filter that rejects all keys.
+    pub fn set_write_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&K) -> bool + Send + Sync + 'static,
+    {
+        *self.top().write_filter.lock_recover() = Some(Arc::new(filter));
+    }
+
     /// Retrieve value associated with the first appearance of `key` in the chain
-    pub fn get(&self, key: &K) -> Option<V> {
-        let mut r = &self.head;
-        while let Some(m) = r {
-            match m.elem.lock().unwrap().get(&key) {
-                None => r = &m.next,
-                Some(val) => return Some(val.clone()),
-            }
+    ///
+    /// As with `HashMap::get`, `key` may be any borrowed form of the key type.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        if let Some(memo) = &self.memo {
+            return self.get_memoized(memo, key);
         }
-        None
+        self.get_from(Some(self.link()), key)
     }
 
-    /// Check associated value only in topmost maps: stops at the first non-fallthrough level
-    pub fn local_get(&self, key: &K) -> Option<V> {
-        let mut r = &self.head;
+    /// Same as `get` for each of `keys`, in a single traversal of the chain
+    ///
+    /// Each layer is locked at most once, however many keys it resolves.
+    /// Read caches are neither consulted nor filled.
+    pub fn get_many<Q>(&self, keys: &[&Q]) -> Vec<Option<V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut values = vec![None; keys.len()];
+        let mut pending = (0..keys.len()).collect::<Vec<_>>();
+        let mut r = Some(self.link());
         while let Some(m) = r {
-            match m.elem.lock().unwrap().get(&key) {
-                None => {
-                    if m.fallthrough {
-                        r = &m.next;
-                    } else {
-                        return None;
+            if pending.is_empty() {
+                break;
+            }
+            if !m.empty.load(Ordering::Relaxed) {
+                let layer = m.elem.lock_recover();
+                pending.retain(|&i| match layer.get(keys[i]) {
+                    Some(val) => {
+                        values[i] = Some(val.clone());
+                        false
                     }
-                }
-                Some(val) => return Some(val.clone()),
+                    None => true,
+                });
             }
+            if m.hiding.load(Ordering::Relaxed) {
+                let tombstones = m.tombstones.lock_recover();
+                pending.retain(|&i| !tombstones.contains(keys[i]));
+            }
+            r = m.next.as_ref();
         }
-        unreachable!()
+        values
     }
 
-    /// Replace old value with new
-    /// # Panics
-    /// - if `key` does not already exist
-    /// - if first layer with `key` is locked
-    /// - if `key` is only found after a write-protected layer
-    pub fn update(&mut self, key: &K, newval: V) {
-        let mut r = &self.head;
+    /// Same as `get`, but start at the layer given by the routing table, see `set_routing`
+    pub fn get_routed<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        V: Clone,
+        K: Clone,
+    {
+        self.get_from(self.route(key), key)
+    }
+
+    /// Lookup of `get`, starting at layer `r`
+    fn get_from<Q>(&self, mut r: Option<&Rc<Node<K, V>>>, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut caches = Vec::new();
+        let mut generations = Vec::new();
         while let Some(m) = r {
-            if m.write_auth.load(Ordering::Relaxed) {
-                match m.elem.lock().unwrap().get_mut(&key) {
-                    None => r = &m.next,
-                    Some(val) => {
-                        if m.unlocked.load(Ordering::Relaxed) {
-                            *val = newval;
-                            return;
-                        } else {
-                            panic!("Key is locked, failed to update");
-                        }
+            generations.push(m.generation.load(Ordering::Relaxed));
+            let found = match m.lookup(key) {
+                Some(val) => Some(val),
+                None if m.hides(key) => return None,
+                None => m.cached(key, (!caches.is_empty()).then_some(&mut generations)),
+            };
+            match found {
+                None => {
+                    if m.cache.is_some() {
+                        caches.push((m, generations.len()));
                     }
+                    r = m.next.as_ref();
+                }
+                Some(val) => {
+                    if !caches.is_empty() {
+                        m.with_stored_key(key, |key| {
+                            for (cache, below) in caches {
+                                cache.remember(key, &val, &generations[below..]);
+                            }
+                        });
+                    }
+                    return Some(val);
                 }
-            } else {
-                break;
             }
         }
-        panic!("Key does not exist, failed to update");
+        None
     }
 
-    /// Replace old value with new, create binding in topmost map if `key` does not exist
-    /// or if first layer with `key` is locked or if `key` is only accessible after a
-    /// write-protected layer.
-    pub fn update_or(&mut self, key: &K, newval: V) {
-        let mut r = &self.head;
+    /// Same as `get`, but only probe the layers up to depth `max_depth`, the toplevel being `0`
+    ///
+    /// Fails if `key` is not bound in any of these layers while the chain goes deeper,
+    /// since the answer would then depend on layers that were not probed.
+    pub fn get_within<Q>(&self, key: &Q, max_depth: usize) -> Result<Option<V>, DepthExceeded>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut r = Some(self.link());
+        let mut depth = self.link_depth();
+        while let Some(m) = r {
+            if depth > max_depth {
+                return Err(DepthExceeded { max_depth });
+            }
+            if let Some(val) = m.lookup(key) {
+                return Ok(Some(val));
+            }
+            if m.hides(key) {
+                return Ok(None);
+            }
+            if let Some(val) = m.cached(key, None) {
+                return Ok(Some(val));
+            }
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        Ok(None)
+    }
+
+    /// Same as `get`, but also report every layer visited and why the lookup went past it
+    pub fn get_traced<Q>(&self, key: &Q) -> (Option<V>, ResolutionTrace)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.traced(key, Lookup::Get, |_| true)
+    }
+
+    /// Same as `local_get`, but also report every layer visited and where the lookup stopped
+    pub fn local_get_traced<Q>(&self, key: &Q) -> (Option<V>, ResolutionTrace)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.traced(key, Lookup::Local, |_| true)
+    }
+
+    /// Report the layer that `update(key, _)` would modify, or where it would be stopped
+    ///
+    /// Write-up promotion is not taken into account, as in `is_effectively_writable`.
+    pub fn update_trace<Q>(&self, key: &Q) -> ResolutionTrace
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        V: Clone,
+    {
+        self.traced(key, Lookup::Update, |m| m.can_write_borrowed(key)).1
+    }
+
+    fn traced<Q, W>(
+        &self,
+        key: &Q,
+        lookup: Lookup,
+        can_write: W,
+    ) -> (Option<V>, ResolutionTrace)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+        W: Fn(&Node<K, V>) -> bool,
+    {
+        let mut r = Some(self.link());
+        let mut trace = ResolutionTrace::default();
+        let mut depth = self.link_depth();
+        if self.overlay_pending() {
+            trace.steps.push(TraceStep {
+                depth: 0,
+                outcome: Outcome::Missing,
+                fallthrough: true,
+                locked: false,
+                readonly: false,
+            });
+        }
+        while let Some(m) = r {
+            let writable = can_write(m);
+            let found = if writable {
+                m.elem.lock_recover().get(key).cloned()
+            } else {
+                None
+            };
+            let outcome = if !writable {
+                Outcome::ReadOnlyStop
+            } else if found.is_some() {
+                Outcome::Found
+            } else if m.hides(key) {
+                Outcome::Masked
+            } else if lookup == Lookup::Local && !m.fallthrough {
+                Outcome::ScopeStop
+            } else {
+                Outcome::Missing
+            };
+            trace.steps.push(TraceStep {
+                depth,
+                outcome,
+                fallthrough: m.fallthrough,
+                locked: !m.unlocked.load(Ordering::Relaxed),
+                readonly: !m.write_auth.load(Ordering::Relaxed),
+            });
+            if outcome != Outcome::Missing {
+                return (found, trace);
+            }
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        (None, trace)
+    }
+
+    /// Retrieve all versions of the value associated with `key`, oldest first
+    ///
+    /// Versions are taken from the same layer as `get`, the last one is the current value.
+    /// Only append-only layers created with `extend_log` keep previous versions.
+    pub fn history<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            if let Some(val) = m.elem.lock_recover().get(key) {
+                let mut versions = match &*m.history.lock_recover() {
+                    Some(history) => history.versions.get(key).cloned().unwrap_or_default(),
+                    None => Vec::new(),
+                };
+                versions.push(val.clone());
+                return versions;
+            }
+            if m.hides(key) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        Vec::new()
+    }
+
+    /// Retrieve the stored key along with its value, from the first layer that contains `key`
+    ///
+    /// Useful when keys carry data that does not take part in their comparison,
+    /// such as the location where a name was defined.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            match m.elem.lock_recover().get_key_value(key) {
+                None if m.hides(key) => return None,
+                None => r = m.next.as_ref(),
+                Some((k, v)) => return Some((k.clone(), v.clone())),
+            }
+        }
+        None
+    }
+
+    /// Values bound to `key` in every layer, from the toplevel down
+    ///
+    /// The first value is the one `get` returns, the others are shadowed by it.
+    /// Layers below a tombstone for `key` are not considered.
+    pub fn get_all<Q>(&self, key: &Q) -> Vec<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut r = Some(self.link());
+        let mut values = Vec::new();
+        while let Some(m) = r {
+            values.extend(m.lookup(key));
+            if m.hides(key) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        values
+    }
+
+    /// Check whether any layer of the chain contains `key`
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            if m.elem.lock_recover().contains_key(key) {
+                return true;
+            }
+            if m.hides(key) {
+                return false;
+            }
+            r = m.next.as_ref();
+        }
+        false
+    }
+
+    /// Check associated value only in topmost maps: stops at the first non-fallthrough level
+    pub fn local_get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            match m.lookup(key) {
+                None => {
+                    if m.fallthrough && !m.hides(key) {
+                        r = m.next.as_ref();
+                    } else {
+                        return None;
+                    }
+                }
+                Some(val) => return Some(val),
+            }
+        }
+        None
+    }
+
+    /// Determine whether `key` is local, captured from an enclosing scope, global, or unbound
+    ///
+    /// Bindings of the root are `Local` rather than `Global` when the root is part of the
+    /// handle's own scope.
+    pub fn classify<Q>(&self, key: &Q) -> Binding
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        let mut depth = 0;
+        while let Some(m) = r {
+            if m.elem.lock_recover().contains_key(key) {
+                return match (depth, &m.next) {
+                    (0, _) => Binding::Local,
+                    (_, None) => Binding::Global,
+                    (depth, Some(_)) => Binding::Captured { depth },
+                };
+            }
+            if m.hides(key) {
+                break;
+            }
+            if !m.fallthrough {
+                depth += 1;
+            }
+            r = m.next.as_ref();
+        }
+        Binding::Unbound
+    }
+
+    /// Describe the layer from which `get(key)` would read, if any
+    pub fn flags_for<Q>(&self, key: &Q) -> Option<KeyFlags>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        let mut depth = self.link_depth();
+        while let Some(m) = r {
+            if m.elem.lock_recover().contains_key(key) {
+                return Some(KeyFlags {
+                    depth,
+                    locked: !m.unlocked.load(Ordering::Relaxed),
+                    readonly: !m.write_auth.load(Ordering::Relaxed),
+                    fallthrough: m.fallthrough,
+                });
+            }
+            if m.hides(key) {
+                break;
+            }
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Select the keys of `referenced` that are not bound locally
+    ///
+    /// These are the free variables of a scope that would reference `referenced`: they are
+    /// either captured, global, or unbound, which `classify` can tell apart.
+    /// Keys are returned in order of first appearance, without duplicates.
+    pub fn free_variables<I>(&self, referenced: I) -> Vec<K>
+    where
+        I: IntoIterator<Item = K>,
+        K: Clone,
+    {
+        let mut seen = HashSet::new();
+        referenced
+            .into_iter()
+            .filter(|k| self.classify(k) != Binding::Local && seen.insert(k.clone()))
+            .collect()
+    }
+
+    /// Replace old value with new
+    ///
+    /// If write-up promotion is enabled, see `set_write_up_promotion`, a `key` only found after
+    /// a write-protected layer is bound in the toplevel instead.
+    /// # Panics
+    /// - if `key` does not already exist
+    /// - if first layer with `key` is locked
+    /// - if `key` is only found after a write-protected layer, and write-up promotion is disabled
+    pub fn update<Q>(&mut self, key: &Q, newval: V)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        if let Err(err) = self.try_update(key, newval) {
+            panic!("{}, failed to update", err);
+        }
+    }
+
+    /// Same as `update`, but report failures instead of panicking
+    pub fn try_update<Q>(&mut self, key: &Q, newval: V) -> Result<(), ChainMapError>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
         while let Some(m) = r {
-            if m.write_auth.load(Ordering::Relaxed) {
-                match m.elem.lock().unwrap().get_mut(&key) {
-                    None => r = &m.next,
+            m.forget(key);
+            if m.can_write_borrowed(key) {
+                let mut layer = m.elem.lock_recover();
+                match layer.get_mut(key) {
+                    None if m.hides(key) => break,
+                    None => r = m.next.as_ref(),
                     Some(val) => {
                         if m.unlocked.load(Ordering::Relaxed) {
-                            *val = newval;
+                            let old = std::mem::replace(val, newval);
+                            m.archive_at(&layer, key, old);
+                            m.touch_key(key);
+                            return Ok(());
+                        } else {
+                            return Err(ChainMapError::KeyLocked);
+                        }
+                    }
+                }
+            } else {
+                if !Self::from_head(Rc::clone(m)).contains_key(key) {
+                    break;
+                }
+                if self.root_node().promote.load(Ordering::Relaxed) {
+                    return self.try_insert(key.to_owned(), newval).map(|_| ());
+                }
+                return Err(ChainMapError::ReadOnlyBarrier);
+            }
+        }
+        Err(ChainMapError::KeyMissing)
+    }
+
+    /// Same as `update`, but modify the value in place with `f`
+    ///
+    /// `f` runs while the layer that contains `key` is locked. With write-up promotion,
+    /// a value only found after a write-protected layer is copied to the toplevel first.
+    /// # Panics
+    /// Same cases as `update`
+    pub fn update_with<Q, F>(&mut self, key: &Q, f: F)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        F: FnOnce(&mut V),
+        V: Clone,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            m.forget(key);
+            if m.can_write_borrowed(key) {
+                let mut layer = m.elem.lock_recover();
+                match layer.get_mut(key) {
+                    None if m.hides(key) => break,
+                    None => r = m.next.as_ref(),
+                    Some(val) => {
+                        if m.unlocked.load(Ordering::Relaxed) {
+                            let old = m.history.lock_recover().is_some().then(|| val.clone());
+                            f(val);
+                            if let Some(old) = old {
+                                m.archive_at(&layer, key, old);
+                            }
+                            m.touch_key(key);
                             return;
                         } else {
-                            break;
+                            panic!("Key is locked, failed to update");
                         }
                     }
                 }
             } else {
+                if self.root_node().promote.load(Ordering::Relaxed) {
+                    if let Some(mut val) = Self::from_head(Rc::clone(m)).get(key) {
+                        f(&mut val);
+                        self.insert(key.to_owned(), val);
+                        return;
+                    }
+                }
                 break;
             }
         }
-        self.insert(key.clone(), newval);
+        panic!("Key does not exist, failed to update");
     }
 
-    /// Allows next element to be seen by `local_get`
-    fn extend_fallthrough(&self) -> Self {
-        Self {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(HashMap::new()),
-                next: self.head.clone(),
-                fallthrough: true,
-                unlocked: AtomicBool::new(true),
-                write_auth: AtomicBool::new(true),
-            })),
+    /// Exchange the values of `a` and `b`, in whichever layers bind them
+    ///
+    /// Same rules as `update` for each of the keys. When they are bound in different layers,
+    /// the upper layer is always locked first, so that concurrent swaps cannot deadlock.
+    /// # Panics
+    /// - if either key does not exist, or is only found after a write-protected layer
+    /// - if the first layer with either key is locked
+    pub fn swap<Q>(&mut self, a: &Q, b: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let (depth_a, node_a) = self.writable_layer(a, "swap");
+        let (depth_b, node_b) = self.writable_layer(b, "swap");
+        if a == b {
+            return;
         }
+        if Rc::ptr_eq(node_a, node_b) {
+            let mut map = node_a.elem.lock_recover();
+            let (ka, va) = map.remove_entry(a).unwrap();
+            let (kb, vb) = map.remove_entry(b).unwrap();
+            node_a.archive_copy(&ka, &va);
+            node_a.archive_copy(&kb, &vb);
+            map.insert(ka, vb);
+            map.insert(kb, va);
+        } else {
+            let ((upper, ku), (lower, kl)) = if depth_a < depth_b {
+                ((node_a, a), (node_b, b))
+            } else {
+                ((node_b, b), (node_a, a))
+            };
+            let mut upper_map = upper.elem.lock_recover();
+            let mut lower_map = lower.elem.lock_recover();
+            upper.archive_copy_at(&upper_map, ku);
+            lower.archive_copy_at(&lower_map, kl);
+            let vu = upper_map.get_mut(ku).unwrap();
+            let vl = lower_map.get_mut(kl).unwrap();
+            std::mem::swap(vu, vl);
+        }
+        node_a.touch();
+        node_b.touch();
     }
 
-    pub fn extend(&self) -> Self {
-        Self {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(HashMap::new()),
-                next: self.head.clone(),
-                fallthrough: false,
-                unlocked: AtomicBool::new(true),
-                write_auth: AtomicBool::new(true),
-            })),
+    /// Replace the value associated with `key` by `new`, only if it is equal to `expected`
+    ///
+    /// Same rules as `update`. The layer that binds `key` stays locked between the comparison
+    /// and the replacement, `new` is given back if the current value is different.
+    /// # Panics
+    /// Same cases as `swap`
+    pub fn compare_and_swap<Q>(&mut self, key: &Q, expected: &V, new: V) -> Result<(), V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: PartialEq,
+    {
+        let (_, node) = self.writable_layer(key, "compare and swap");
+        let mut map = node.elem.lock_recover();
+        let val = map.get_mut(key).unwrap();
+        if val != expected {
+            return Err(new);
         }
+        let old = std::mem::replace(val, new);
+        node.archive_at(&map, key, old);
+        node.touch_key(key);
+        Ok(())
+    }
+
+    /// Layer that `update` would modify for `key`, along with its depth
+    /// # Panics
+    /// Same cases as `update`, ignoring write-up promotion
+    fn writable_layer<Q>(&self, key: &Q, action: &str) -> (usize, &Rc<Node<K, V>>)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        let mut depth = 0;
+        // Write filters take the stored form of the key, they are checked once it is found
+        let mut filtered = Vec::new();
+        while let Some(m) = r {
+            m.forget(key);
+            if !m.write_auth.load(Ordering::Relaxed) {
+                break;
+            }
+            if m.has_write_filter() {
+                filtered.push(m);
+            }
+            if let Some((stored, _)) = m.elem.lock_recover().get_key_value(key) {
+                if !filtered.iter().all(|f| f.can_write(stored)) {
+                    break;
+                }
+                if !m.unlocked.load(Ordering::Relaxed) {
+                    panic!("Key is locked, failed to {}", action);
+                }
+                return (depth, m);
+            }
+            if m.hides(key) {
+                break;
+            }
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        panic!("Key does not exist, failed to {}", action);
+    }
+
+    /// Same as `get`, but borrow the value instead of cloning it
+    ///
+    /// Works for values that cannot be cloned. The layer that binds `key` stays locked
+    /// for as long as the guard is alive, so the guard should not be kept across other
+    /// operations on the chain. Read caches are not consulted.
+    pub fn get_ref<Q>(&self, key: &Q) -> Option<ValueGuard<'_, K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            if !m.empty.load(Ordering::Relaxed) && m.may_contain(key) {
+                let layer = m.elem.lock_recover();
+                if let Some((k, _)) = layer.get_key_value(key) {
+                    let key = k.clone();
+                    return Some(ValueGuard { layer, key });
+                }
+            }
+            if m.hides(key) {
+                return None;
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Mutable access to the value associated with `key`, in whichever layer binds it
+    ///
+    /// Same rules as `update`: `None` if `key` does not exist or is only found after
+    /// a write-protected layer. The layer stays locked for as long as the guard is alive.
+    /// # Panics
+    /// Panics if first layer with `key` is locked
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<ValueGuardMut<'_, K, V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            m.forget(key);
+            if !m.can_write_borrowed(key) {
+                return None;
+            }
+            let layer = m.elem.lock_recover();
+            match layer.get_key_value(key) {
+                None if m.hides(key) => return None,
+                None => r = m.next.as_ref(),
+                Some((stored, val)) => {
+                    if !m.unlocked.load(Ordering::Relaxed) {
+                        panic!("Key is locked, could not borrow mutably");
+                    }
+                    // The guard grants mutable access, we have to assume it will be used
+                    m.archive_copy(stored, val);
+                    m.touch_key(key);
+                    let key = stored.clone();
+                    return Some(ValueGuardMut { layer, key });
+                }
+            }
+        }
+        None
+    }
+
+    /// Choose whether `update` shadows bindings that it cannot modify because of a
+    /// write-protected layer, instead of panicking
+    ///
+    /// This is the copy-on-write behavior of prototype chains: a value inherited from
+    /// a read-only prototype is overridden locally when assigned. The setting applies
+    /// to all handles sharing the same root. `update_or` is not affected, since it
+    /// already behaves this way.
+    pub fn set_write_up_promotion(&self, enabled: bool) {
+        self.root_node().promote.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn has_write_up_promotion(&self) -> bool {
+        self.root_node().promote.load(Ordering::Relaxed)
+    }
+
+    /// Hand the bindings of the toplevel to `f` once the layer is dropped
+    ///
+    /// The layer is dropped when no handle uses it anymore, either as toplevel or as a
+    /// lower layer of another chain. Replaces any hook previously set on the same layer.
+    /// Copies of the layer made by `clone` do not inherit the hook.
+    pub fn on_drop<F>(&self, f: F)
+    where
+        F: FnOnce(HashMap<K, V>) + 'static,
+    {
+        *self.top().on_drop.lock_recover() = Some(Box::new(f));
+    }
+
+    /// Check whether `update(key, _)` would succeed right now
+    ///
+    /// This is the case if `key` exists, its first layer is not locked,
+    /// and it is not only found after a write-protected layer.
+    /// Write-up promotion is not taken into account: the existing binding can not be modified.
+    pub fn is_effectively_writable<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        let mut filtered = Vec::new();
+        while let Some(m) = r {
+            if !m.write_auth.load(Ordering::Relaxed) {
+                return false;
+            }
+            if m.has_write_filter() {
+                filtered.push(m);
+            }
+            if let Some((stored, _)) = m.elem.lock_recover().get_key_value(key) {
+                return filtered.iter().all(|f| f.can_write(stored))
+                    && m.unlocked.load(Ordering::Relaxed);
+            }
+            if m.hides(key) {
+                return false;
+            }
+            r = m.next.as_ref();
+        }
+        false
+    }
+
+    /// Replace old value with new, create binding in topmost map if `key` does not exist
+    /// or if first layer with `key` is locked or if `key` is only accessible after a
+    /// write-protected layer.
+    pub fn update_or<Q>(&mut self, key: &Q, newval: V)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        self.update_or_report(key, newval);
+    }
+
+    /// Same as `update_or`, but tell whether the binding was updated or shadowed, and why
+    pub fn update_or_report<Q>(&mut self, key: &Q, newval: V) -> UpdateOutcome
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        let outcome = loop {
+            let m = match r {
+                Some(m) => m,
+                None => break UpdateOutcome::InsertedFresh,
+            };
+            m.forget(key);
+            if !m.can_write_borrowed(key) {
+                let mut below = r;
+                while let Some(b) = below {
+                    if b.elem.lock_recover().contains_key(key) {
+                        break;
+                    }
+                    below = if b.hides(key) { None } else { b.next.as_ref() };
+                }
+                break match below {
+                    Some(_) => UpdateOutcome::ShadowedDueToReadonly,
+                    None => UpdateOutcome::InsertedFresh,
+                };
+            }
+            let mut layer = m.elem.lock_recover();
+            match layer.get_mut(key) {
+                None if m.hides(key) => break UpdateOutcome::InsertedFresh,
+                None => r = m.next.as_ref(),
+                Some(val) => {
+                    if m.unlocked.load(Ordering::Relaxed) {
+                        let old = std::mem::replace(val, newval);
+                        m.archive_at(&layer, key, old);
+                        m.touch_key(key);
+                        return UpdateOutcome::Updated;
+                    } else {
+                        break UpdateOutcome::ShadowedDueToLock;
+                    }
+                }
+            }
+        };
+        self.insert(key.to_owned(), newval);
+        outcome
+    }
+
+    /// Move the value bound to `old` so that it is bound to `new` instead
+    ///
+    /// Only the layer from which `get(old)` reads is affected, or every layer that binds `old`
+    /// if `deep` is set. Bindings are renamed under the same conditions as `update` can modify
+    /// them: locked layers are skipped, and nothing past a write-protected layer is renamed.
+    /// An existing binding of `new` in a renamed layer is overwritten.
+    /// Returns the number of layers in which the binding was renamed.
+    pub fn rename_key<Q>(&mut self, old: &Q, new: K, deep: bool) -> usize
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        let mut renamed = 0;
+        while let Some(m) = r {
+            m.forget(old);
+            m.forget::<K>(&new);
+            if !m.can_write_borrowed(old) {
+                break;
+            }
+            let mut layer = m.elem.lock_recover();
+            if layer.contains_key(old) {
+                if m.unlocked.load(Ordering::Relaxed) {
+                    let val = layer.remove(old).unwrap();
+                    if let Some(prev) = layer.insert(new.clone(), val) {
+                        m.archive(&new, prev);
+                    }
+                    if let Some(history) = &mut *m.history.lock_recover() {
+                        if let Some(versions) = history.versions.remove(old) {
+                            history.versions.entry(new.clone()).or_default().extend(versions);
+                        }
+                    }
+                    m.touch();
+                    renamed += 1;
+                }
+                if !deep {
+                    break;
+                }
+            }
+            if m.hides(old) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        renamed
+    }
+
+    /// Remove visible bindings that are not reachable from `roots`
+    ///
+    /// Values are treated as objects that reference other keys: `trace` is called on the value
+    /// of every reachable key, and must report the keys it references through its second argument.
+    /// Unreachable bindings are only removed if `update` could modify them, bindings behind
+    /// a lock or a write-protected layer are left untouched, as are shadowed bindings.
+    /// Returns the keys that were removed.
+    pub fn mark_and_collect<R, T>(&mut self, roots: R, trace: T) -> Vec<K>
+    where
+        R: IntoIterator<Item = K>,
+        T: Fn(&V, &mut dyn FnMut(&K)),
+        V: Clone,
+        K: Clone,
+    {
+        let mut marked = HashSet::new();
+        let mut pending = roots.into_iter().collect::<Vec<_>>();
+        while let Some(key) = pending.pop() {
+            if !marked.contains(&key) {
+                if let Some(val) = self.get(&key) {
+                    trace(&val, &mut |k| pending.push(k.clone()));
+                }
+                marked.insert(key);
+            }
+        }
+        let mut removed = Vec::new();
+        for key in self.key_set() {
+            if marked.contains(&key) || !self.is_effectively_writable(&key) {
+                continue;
+            }
+            let mut r = Some(self.link());
+            while let Some(m) = r {
+                m.forget(&key);
+                if m.elem.lock_recover().remove(&key).is_some() {
+                    m.touch_key(&key);
+                    removed.push(key);
+                    break;
+                }
+                r = m.next.as_ref();
+            }
+        }
+        removed
+    }
+
+    pub fn extend(&self) -> Self {
+        self.child(Node::new(HashMap::new(), Some(self.settled_link().clone()), false))
+    }
+
+    /// Create a new append-only scope
+    ///
+    /// Values replaced in this layer by `insert`, `update` or `update_or` are not lost:
+    /// they are kept as previous versions and can be retrieved with `history`.
+    /// Modifications made in place through `entry` are not versioned.
+    pub fn extend_log(&self) -> Self
+    where
+        V: Clone,
+        K: Clone,
+    {
+        let node = Node::new(HashMap::new(), Some(self.settled_link().clone()), false);
+        *node.history.lock_recover() = Some(History {
+            versions: HashMap::new(),
+            copy: V::clone,
+            copy_key: K::clone,
+        });
+        self.child(node)
     }
 
     /// Create a new scope, initialized with or without bindings.
@@ -315,22 +1879,11 @@ where
     /// check_that!(local_get? layer has 2 and not 0,1,3);
     /// ```
     pub fn extend_with(&self, h: HashMap<K, V>) -> Self {
-        Self {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(h),
-                next: self.head.clone(),
-                fallthrough: false,
-                unlocked: AtomicBool::new(true),
-                write_auth: AtomicBool::new(true),
-            })),
-        }
+        self.child(Node::new(h, Some(self.settled_link().clone()), false))
     }
 
     pub fn fork(&mut self) -> Self {
-        let newlevel = self.extend();
-        let oldlevel = self.extend_fallthrough();
-        let _ = std::mem::replace(&mut *self, oldlevel);
-        newlevel
+        self.fork_with(HashMap::new())
     }
 
     ///
@@ -339,6 +1892,10 @@ where
     ///
     /// Updates, however, are visible.
     ///
+    /// The fallthrough layer that receives later bindings made to `self` is only created
+    /// when it is first needed, so that forks of a parent that is never modified again
+    /// do not make the chain any deeper.
+    ///
     /// # Examples
     ///
     /// ```
@@ -412,55 +1969,656 @@ where
     /// check_that!(local_get? layer has 1 and not 0,2);
     ///```
     pub fn fork_with(&mut self, h: HashMap<K, V>) -> Self {
-        let newlevel = self.extend_with(h);
-        let oldlevel = self.extend_fallthrough();
-        let _ = std::mem::replace(&mut *self, oldlevel);
+        self.head = self.link().clone();
+        let newlevel = self.child(Node::new(h, Some(self.head.clone()), false));
+        self.overlay = Some(OnceLock::new());
         newlevel
     }
 
+    /// Duplicate the toplevel as a new scope of its own
+    ///
+    /// Same as `clone`, except that the copy is never fallthrough: `local_get` on the copy
+    /// stops at its toplevel even if the original's continues to the next layer.
+    pub fn clone_as_branch(&self) -> Self
+    where
+        V: Clone,
+        K: Clone,
+    {
+        let mut copy = self.clone();
+        if copy.is_fallthrough() {
+            copy.set_fallthrough(false);
+        }
+        copy
+    }
+
+    /// Create another handle to the same toplevel
+    ///
+    /// Unlike `clone`, which copies the toplevel, bindings made through either handle
+    /// are visible to both.
+    pub fn clone_linked(&self) -> Self {
+        let mut linked = Self::from_head(self.settled_link().clone());
+        linked.quota = self.quota.clone();
+        linked
+    }
+
     /// Gather all keys in a single `HashMap`.
     ///
     /// Only keys accessible through a direct path are considered:
     /// if we `let map = chain.collect()` then for all `k` valid keys, `map.get(&k) == chain.get(&k)`.
-    pub fn collect(&self) -> HashMap<K, V> {
-        let mut r = &self.head;
-        let mut layers = Vec::new();
-        while let Some(m) = r {
-            layers.push(&m.elem);
-            r = &m.next;
-        }
+    pub fn collect(&self) -> HashMap<K, V>
+    where
+        V: Clone,
+        K: Clone,
+    {
         let mut map = HashMap::new();
-        for l in layers.into_iter().rev() {
-            map.extend(l.lock().unwrap().clone())
-        }
+        self.collect_into(&mut map);
         map
     }
-}
 
-impl<K, V> Clone for ChainMap<K, V>
-where
-    K: Clone + Hash + Eq,
-    V: Clone,
-{
-    fn clone(&self) -> Self {
-        ChainMap {
-            head: Some(Rc::new(Node {
-                elem: Mutex::new(self.head.as_ref().unwrap().elem.lock().unwrap().clone()),
-                next: self.head.as_ref().unwrap().next.clone(),
-                fallthrough: self.head.as_ref().unwrap().fallthrough,
-                unlocked: AtomicBool::new(self.head.as_ref().unwrap().unlocked.load(Ordering::Relaxed)),
-                write_auth: AtomicBool::new(self.head.as_ref().unwrap().write_auth.load(Ordering::Relaxed)),
-            })),
+    /// Same as `collect`, but reuses the allocation of `out`.
+    ///
+    /// `out` is cleared before being filled, which makes this suitable for taking
+    /// a snapshot at every iteration of a loop without allocating a new map each time.
+    pub fn collect_into(&self, out: &mut HashMap<K, V>)
+    where
+        V: Clone,
+        K: Clone,
+    {
+        out.clear();
+        let mut hidden = HashSet::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if !out.contains_key(k) && !hidden.contains(k) {
+                    out.insert(k.clone(), v.clone());
+                }
+            }
+            m.hidden_keys(&mut hidden);
+            r = m.next.as_ref();
         }
     }
-}
 
-#[cfg(test)]
-#[cfg_attr(tarpaulin, skip)]
-mod test {
-    use super::*;
-    macro_rules! map {
-        ( $( $key:expr => $val:expr ),* ) => {
+    /// Same as `collect`, but into a map sorted by key
+    pub fn collect_btree(&self) -> BTreeMap<K, V>
+    where
+        K: Ord,
+        V: Clone,
+        K: Clone,
+    {
+        let mut map = BTreeMap::new();
+        let mut hidden = HashSet::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if !hidden.contains(k) {
+                    map.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+            m.hidden_keys(&mut hidden);
+            r = m.next.as_ref();
+        }
+        map
+    }
+
+    /// Same as `collect`, but also list the keys bound to different values in several layers
+    ///
+    /// Each conflict holds all values bound to the key, from the toplevel down, the first
+    /// one being the value kept in the map. Keys bound several times to the same value
+    /// are not reported.
+    pub fn collect_checked(&self) -> (HashMap<K, V>, Vec<Conflict<K, V>>)
+    where
+        V: PartialEq,
+        V: Clone,
+        K: Clone,
+    {
+        let mut all = HashMap::<K, Vec<V>>::new();
+        let mut hidden = HashSet::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if !hidden.contains(k) {
+                    all.entry(k.clone()).or_default().push(v.clone());
+                }
+            }
+            m.hidden_keys(&mut hidden);
+            r = m.next.as_ref();
+        }
+        let mut map = HashMap::with_capacity(all.len());
+        let mut conflicts = Vec::new();
+        for (k, vals) in all {
+            map.insert(k.clone(), vals[0].clone());
+            if vals.iter().any(|v| *v != vals[0]) {
+                conflicts.push((k, vals));
+            }
+        }
+        (map, conflicts)
+    }
+
+    /// Gather all accessible bindings, sorted by key according to `cmp`
+    pub fn collect_sorted_by<F>(&self, cmp: F) -> Vec<(K, V)>
+    where
+        F: Fn(&K, &K) -> std::cmp::Ordering,
+        V: Clone,
+        K: Clone,
+    {
+        let mut seen = HashSet::new();
+        let mut bindings = Vec::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if seen.insert(k.clone()) {
+                    bindings.push((k.clone(), v.clone()));
+                }
+            }
+            m.hidden_keys(&mut seen);
+            r = m.next.as_ref();
+        }
+        bindings.sort_unstable_by(|(a, _), (b, _)| cmp(a, b));
+        bindings
+    }
+
+    /// Same as `collect`, but into a map that remembers where each binding came from
+    ///
+    /// Bindings are grouped by the layer that supplies them, starting with the toplevel.
+    /// Within a layer, they are in the iteration order of that layer.
+    #[cfg(feature = "indexmap")]
+    pub fn collect_index(&self) -> indexmap::IndexMap<K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut map = indexmap::IndexMap::new();
+        let mut hidden = HashSet::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            for (k, v) in m.elem.lock_recover().iter() {
+                if !hidden.contains(k) {
+                    map.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+            m.hidden_keys(&mut hidden);
+            r = m.next.as_ref();
+        }
+        map
+    }
+
+    /// Same as `collect`, but the result is cached on this handle.
+    ///
+    /// The flattened map is only rebuilt if a layer was modified since the previous call,
+    /// which makes repeated snapshots of a rarely modified chain cheap.
+    /// Clones of the handle do not share the cache.
+    pub fn collect_cached(&self) -> Arc<HashMap<K, V>>
+    where
+        V: Clone,
+        K: Clone,
+    {
+        let generations = self.generations();
+        let mut flat = self.flat.lock_recover();
+        if let Some(cache) = &*flat {
+            if cache.generations == generations {
+                return Arc::clone(&cache.map);
+            }
+        }
+        let map = Arc::new(self.collect());
+        *flat = Some(FlatCache {
+            generations,
+            map: Arc::clone(&map),
+        });
+        map
+    }
+
+    /// Replace the chain of this handle by a single layer holding the bindings of `collect`
+    ///
+    /// Caps the depth of lookups for handles that were extended many times. Other handles
+    /// are not affected, but no longer share any layer with this one. The new layer keeps
+    /// the lock of the toplevel, while the locks, write protection, history and read caches
+    /// of the other layers are lost. A quota now applies to the new layer as a whole.
+    pub fn flatten(&mut self)
+    where
+        V: Clone,
+        K: Clone,
+    {
+        let node = Node::new(self.collect(), None, false);
+        node.unlocked.store(self.is_unlocked(), Ordering::Relaxed);
+        if self.link().has_bloom() {
+            node.enable_bloom();
+        }
+        #[cfg(feature = "arc-swap")]
+        if let Some(copy_key) = self.link().published_copier() {
+            node.publish(copy_key);
+        }
+        let node = Rc::new(node);
+        if let Some(scope) = &mut self.quota {
+            scope.base = Rc::clone(&node);
+        }
+        self.head = node;
+        self.overlay = None;
+    }
+
+    /// Move the bindings of the toplevel down to its parent, and remove the toplevel
+    ///
+    /// Closes a scope while keeping its exports: the parent replaces any value it had for
+    /// the same keys, and loses those hidden by the toplevel.
+    /// Fails if the toplevel is the root, is used by another handle or is write-protected,
+    /// or if the parent is locked, in which case nothing is modified.
+    pub fn squash_into_parent(&mut self) -> Result<(), ChainMapError>
+    where
+        K: Clone,
+    {
+        let top = self.top_mut().ok_or(ChainMapError::Shared)?;
+        if !top.write_auth.load(Ordering::Relaxed) {
+            return Err(ChainMapError::ReadOnlyBarrier);
+        }
+        let parent = Rc::clone(top.next.as_ref().ok_or(ChainMapError::NoParent)?);
+        if !parent.unlocked.load(Ordering::Relaxed) {
+            return Err(ChainMapError::Locked);
+        }
+        let bindings = std::mem::take(&mut *top.elem.lock_recover());
+        let tombstones = std::mem::take(&mut *top.tombstones.lock_recover());
+        let mut layer = parent.elem.lock_recover();
+        for key in tombstones {
+            parent.forget(&key);
+            if let Some(old) = layer.remove(&key) {
+                parent.archive(&key, old);
+            }
+            // Keep hiding the bindings below the parent
+            if parent.next.is_some() {
+                parent.tombstones.lock_recover().insert(key);
+                parent.hiding.store(true, Ordering::Relaxed);
+            }
+        }
+        for (key, val) in bindings {
+            parent.forget(&key);
+            if let Some(old) = layer.insert(key.clone(), val) {
+                parent.archive(&key, old);
+            }
+        }
+        drop(layer);
+        parent.touch();
+        if let Some(scope) = &mut self.quota {
+            if Rc::ptr_eq(&scope.base, &self.head) {
+                scope.base = Rc::clone(&parent);
+            }
+        }
+        self.head = parent;
+        Ok(())
+    }
+
+    /// Identifier of each layer, from top to bottom
+    pub fn layer_ids(&self) -> Vec<LayerId> {
+        let mut r = Some(self.link());
+        let mut ids = Vec::new();
+        while let Some(m) = r {
+            ids.push(m.id);
+            r = m.next.as_ref();
+        }
+        ids
+    }
+
+    /// Replace all bindings of the layer identified by `id` at once, keeping its position and flags
+    ///
+    /// Meant for layers whose contents are provided by an external source, e.g. settings
+    /// periodically pulled from a remote service. Locks and write protection are not checked:
+    /// they protect bindings from the users of the chain, not from the provider of the layer.
+    /// Returns the previous bindings, or `None` if no layer of the chain has this identifier.
+    pub fn replace_layer_contents(
+        &mut self,
+        id: LayerId,
+        map: HashMap<K, V>,
+    ) -> Option<HashMap<K, V>> {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            if m.id == id {
+                let old = std::mem::replace(&mut *m.elem.lock_recover(), map);
+                for key in old.keys() {
+                    m.forget(key);
+                }
+                m.touch();
+                return Some(old);
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Current generation of each layer, from top to bottom
+    fn generations(&self) -> Vec<usize> {
+        let mut r = Some(self.link());
+        let mut generations = Vec::new();
+        while let Some(m) = r {
+            generations.push(m.generation.load(Ordering::Relaxed));
+            r = m.next.as_ref();
+        }
+        generations
+    }
+
+    /// Gather all accessible keys without cloning any value.
+    ///
+    /// Equivalent to the key set of `collect()`, but cheaper when values are heavyweight.
+    pub fn key_set(&self) -> HashSet<K>
+    where
+        K: Clone,
+    {
+        let mut r = Some(self.link());
+        let mut keys = HashSet::new();
+        let mut hidden = HashSet::new();
+        while let Some(m) = r {
+            keys.extend(
+                m.elem
+                    .lock_recover()
+                    .keys()
+                    .filter(|k| !hidden.contains(*k))
+                    .cloned(),
+            );
+            m.hidden_keys(&mut hidden);
+            r = m.next.as_ref();
+        }
+        keys
+    }
+
+    /// Number of accessible keys, shadowed bindings are only counted once
+    pub fn len(&self) -> usize
+    where
+        K: Clone,
+    {
+        self.key_set().len()
+    }
+
+    /// Check whether the chain has no accessible binding
+    pub fn is_empty(&self) -> bool {
+        let mut upper: Vec<&Node<K, V>> = Vec::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            // Bindings hidden by a layer above are not accessible
+            if m.elem
+                .lock_recover()
+                .keys()
+                .any(|k| !upper.iter().any(|u| u.hides(k)))
+            {
+                return false;
+            }
+            upper.push(&**m);
+            r = m.next.as_ref();
+        }
+        true
+    }
+
+    /// Pick `n` accessible bindings uniformly at random, or all of them if there are fewer
+    ///
+    /// Only the selected values are cloned. Bindings are returned in arbitrary order.
+    #[cfg(feature = "rand")]
+    pub fn sample<R>(&self, n: usize, rng: &mut R) -> Vec<(K, V)>
+    where
+        R: rand::Rng + ?Sized,
+        K: Clone,
+        V: Clone,
+    {
+        let mut reservoir = Vec::with_capacity(n);
+        let mut seen = 0;
+        let mut shadowed = HashSet::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            let layer = m.elem.lock_recover();
+            for (k, v) in layer.iter() {
+                // Shadowed and hidden bindings are not accessible
+                if shadowed.contains(k) {
+                    continue;
+                }
+                if seen < n {
+                    reservoir.push((k.clone(), v.clone()));
+                } else {
+                    let j = rng.gen_range(0, seen + 1);
+                    if j < n {
+                        reservoir[j] = (k.clone(), v.clone());
+                    }
+                }
+                seen += 1;
+            }
+            shadowed.extend(layer.keys().cloned());
+            drop(layer);
+            m.hidden_keys(&mut shadowed);
+            r = m.next.as_ref();
+        }
+        reservoir
+    }
+
+    /// Iterate over the bindings of the toplevel, along with the value each of them shadows
+    ///
+    /// The shadowed value is the one `get` would return if the toplevel did not bind the key.
+    pub fn local_overrides(&self) -> impl Iterator<Item = (K, V, Option<V>)>
+    where
+        V: Clone,
+        K: Clone,
+    {
+        let top = self.link();
+        let below = top.next.clone().map(Self::from_head);
+        let overrides = top
+            .elem
+            .lock_recover()
+            .iter()
+            .map(|(k, v)| {
+                let shadowed = below.as_ref().and_then(|below| below.get(k));
+                (k.clone(), v.clone(), shadowed)
+            })
+            .collect::<Vec<_>>();
+        overrides.into_iter()
+    }
+
+    /// Count how many layers of the chain bind `key`
+    ///
+    /// Only the first one is visible through `get`, all others are shadowed.
+    /// Layers below a tombstone for `key` are not considered.
+    pub fn occurrences<Q>(&self, key: &Q) -> usize
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(self.link());
+        let mut count = 0;
+        while let Some(m) = r {
+            if m.elem.lock_recover().contains_key(key) {
+                count += 1;
+            }
+            if m.hides(key) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        count
+    }
+}
+
+impl<K, V> ChainMap<K, Arc<V>>
+where
+    K: Eq + Hash + Clone,
+    V: Eq + Hash,
+{
+    /// Get a shared allocation for `val`
+    ///
+    /// Equal values interned through any handle of the same chain share a single allocation,
+    /// which is kept in the pool of the root layer.
+    pub fn intern(&self, val: V) -> Arc<V> {
+        let mut pool = self.root_node().pool.lock_recover();
+        let pool = pool.get_or_insert_with(HashSet::new);
+        match pool.get(&val) {
+            Some(shared) => Arc::clone(shared),
+            None => {
+                let shared = Arc::new(val);
+                pool.insert(Arc::clone(&shared));
+                shared
+            }
+        }
+    }
+
+    /// Create a new binding in the toplevel, sharing the allocation of equal values
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert_interned(&mut self, key: K, val: V) {
+        let shared = self.intern(val);
+        self.insert(key, shared);
+    }
+
+    /// Release pooled values that are no longer referenced outside of the pool
+    pub fn purge_interned(&self) {
+        if let Some(pool) = &mut *self.root_node().pool.lock_recover() {
+            pool.retain(|shared| Arc::strong_count(shared) > 1);
+        }
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + std::borrow::Borrow<str> + for<'a> From<&'a str>,
+    V: Clone,
+{
+    /// Create a new binding in the toplevel from a borrowed key
+    ///
+    /// Intended for `Arc<str>` and `Rc<str>` keys: if `key` is already bound anywhere
+    /// in the chain, its allocation is shared instead of creating a new one.
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert_str(&mut self, key: &str, val: V) -> Option<V> {
+        let mut r = Some(self.link());
+        let mut shared = None;
+        while let Some(m) = r {
+            if let Some((k, _)) = m.elem.lock_recover().get_key_value(key) {
+                shared = Some(k.clone());
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        self.insert(shared.unwrap_or_else(|| K::from(key)), val)
+    }
+
+    /// Same as `get`, without building a `K` from `key`
+    pub fn get_str(&self, key: &str) -> Option<V> {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            match m.elem.lock_recover().get(key) {
+                None if m.hides(key) => return None,
+                None => r = m.next.as_ref(),
+                Some(val) => return Some(val.clone()),
+            }
+        }
+        None
+    }
+}
+
+impl<K, V> Clone for ChainMap<K, V>
+where
+    K: Clone + Hash + Eq,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        if let Some(overlay) = &self.overlay {
+            if overlay.get().is_none() {
+                // The pending layer would be empty, so it can stay pending in the copy
+                let mut copy = ChainMap::from_head(self.head.clone());
+                copy.overlay = Some(OnceLock::new());
+                copy.quota = self.quota.clone();
+                copy.routing = self.routing.clone();
+                return copy;
+            }
+        }
+        let node = self.link();
+        let elem = node.elem.lock_recover().clone();
+        let mut copy = ChainMap::from_head(Rc::new(Node {
+            id: LayerId(next_generation()),
+            empty: AtomicBool::new(elem.is_empty()),
+            elem: Mutex::new(elem),
+            next: node.next.clone(),
+            fallthrough: node.fallthrough,
+            unlocked: AtomicBool::new(node.unlocked.load(Ordering::Relaxed)),
+            write_auth: AtomicBool::new(node.write_auth.load(Ordering::Relaxed)),
+            write_filter: Mutex::new(node.write_filter.lock_recover().clone()),
+            generation: AtomicUsize::new(next_generation()),
+            pool: Mutex::new(None),
+            history: Mutex::new(node.history.lock_recover().clone()),
+            cache: node
+                .cache
+                .as_ref()
+                .map(|cache| Mutex::new(cache.lock_recover().clone())),
+            promote: AtomicBool::new(node.promote.load(Ordering::Relaxed)),
+            on_drop: Mutex::new(None),
+            tombstones: Mutex::new(node.tombstones.lock_recover().clone()),
+            hiding: AtomicBool::new(node.hiding.load(Ordering::Relaxed)),
+            bloom: OnceLock::new(),
+            #[cfg(feature = "arc-swap")]
+            published: OnceLock::new(),
+        }));
+        if node.has_bloom() {
+            copy.link().enable_bloom();
+        }
+        #[cfg(feature = "arc-swap")]
+        if let Some(copy_key) = node.published_copier() {
+            copy.link().publish(copy_key);
+        }
+        copy.quota = self.quota.clone().map(|mut scope| {
+            // The copied layer takes the place of the original
+            if Rc::ptr_eq(&scope.base, node) {
+                scope.base = copy.head.clone();
+            }
+            scope
+        });
+        copy.routing = self.routing.clone();
+        copy
+    }
+}
+
+impl<K, V> Default for ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Insert into the toplevel, as `insert_all`
+///
+/// Since the inherent method `ChainMap::extend` creates a new layer, this one has to be
+/// called as `Extend::extend(&mut chain, iter)` when the type is known.
+impl<K, V> Extend<(K, V)> for ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.insert_all(iter);
+    }
+}
+
+/// Build a chain with a single layer holding all bindings of the iterator
+impl<K, V> std::iter::FromIterator<(K, V)> for ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self::new_with(iter.into_iter().collect())
+    }
+}
+
+impl<K, V> fmt::Debug for ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + fmt::Debug,
+    V: Clone + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(tarpaulin, skip)]
+mod test {
+    use super::*;
+    macro_rules! map {
+        ( $( $key:expr => $val:expr ),* ) => {
             { let mut h = HashMap::new();
               $( h.insert($key, $val); )*
               h
@@ -478,20 +2636,20 @@ mod test {
         ch0.insert(0, "z0");
         // Note: although this is very ugly, it is only visible internally
         // The exposed API is a lot more friendly.
-        assert_eq!(ch1.head().unwrap().lock().unwrap().get(&0), Some(&"a1"));
-        assert_eq!(ch2.head().unwrap().lock().unwrap().get(&0), Some(&"a2"));
+        assert_eq!(ch1.head().lock().unwrap().get(&0), Some(&"a1"));
+        assert_eq!(ch2.head().lock().unwrap().get(&0), Some(&"a2"));
         let mut ch3a = ch2.extend();
         let ch3b = ch2.extend();
         ch3a.insert(4, "e3a");
         ch2.insert(4, "e2");
-        assert_eq!(ch2.head().unwrap().lock().unwrap().get(&4), Some(&"e2"));
-        assert_eq!(ch3a.head().unwrap().lock().unwrap().get(&4), Some(&"e3a"));
+        assert_eq!(ch2.head().lock().unwrap().get(&4), Some(&"e2"));
+        assert_eq!(ch3a.head().lock().unwrap().get(&4), Some(&"e3a"));
         assert_eq!(
-            ch3a.tail().head().unwrap().lock().unwrap().get(&4),
+            ch3a.tail().head().lock().unwrap().get(&4),
             Some(&"e2")
         );
         assert_eq!(
-            ch3b.tail().head().unwrap().lock().unwrap().get(&4),
+            ch3b.tail().head().lock().unwrap().get(&4),
             Some(&"e2")
         );
     }
@@ -744,4 +2902,2109 @@ mod test {
         assert_eq!(ch1b.get(&0), Some('c'));
         assert_eq!(ch1b.get(&1), Some('b'));
     }
+
+    #[test]
+    fn testing_builders() {
+        let layers = testing::linear(3);
+        assert_eq!(layers.len(), 4);
+        assert_eq!(layers[3].get(&0), Some(3));
+        assert_eq!(layers[3].get(&1), Some(1));
+        assert_eq!(layers[1].get(&2), None);
+        let (root, branches) = testing::forked(2);
+        assert_eq!(branches[0].get(&1), Some(1));
+        assert_eq!(branches[1].get(&1), None);
+        assert_eq!(root.get(&1), None);
+        assert_eq!(branches[1].get(&0), Some(0));
+    }
+
+    #[test]
+    fn key_set() {
+        let ch = ChainMap::new_with(map![0 => 'a', 3 => 'd'])
+            .extend_with(map![1 => 'b'])
+            .extend_with(map![0 => 'e']);
+        let keys = ch.key_set();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.contains(&0) && keys.contains(&1) && keys.contains(&3));
+        assert_eq!(keys, ch.collect().keys().cloned().collect());
+    }
+
+    #[test]
+    fn occurrences() {
+        let ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let ch1 = ch0.extend();
+        let ch2 = ch1.extend_with(map![0 => 'c']);
+        assert_eq!(ch2.occurrences(&0), 2);
+        assert_eq!(ch2.occurrences(&1), 1);
+        assert_eq!(ch2.occurrences(&2), 0);
+        assert_eq!(ch1.occurrences(&0), 1);
+    }
+
+    #[test]
+    fn write_filter() {
+        let ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch1 = ch0.extend_with(map![2 => 'c', 3 => 'd']);
+        ch1.set_write_filter(|k| k % 2 == 0);
+        ch1.update(&0, 'e');
+        ch1.update(&2, 'f');
+        ch1.update_or(&1, 'g');
+        ch1.update_or(&3, 'h');
+        assert_eq!(ch0.get(&0), Some('e'));
+        assert_eq!(ch0.get(&1), Some('b'));
+        assert_eq!(ch1.get(&1), Some('g'));
+        assert_eq!(ch1.get(&2), Some('f'));
+        assert_eq!(ch1.get(&3), Some('h'));
+    }
+
+    #[test]
+    #[should_panic]
+    fn update_despite_write_filter() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend();
+        ch1.set_write_filter(|_| false);
+        ch1.update(&0, 'b');
+    }
+
+    #[test]
+    fn map_parity() {
+        let ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch1 = ch0.extend_with(map![0 => 'c', 2 => 'd']);
+        assert_eq!(ch1.get_key_value(&0), Some((0, 'c')));
+        assert_eq!(ch1.get_key_value(&1), Some((1, 'b')));
+        assert_eq!(ch1.get_key_value(&3), None);
+        assert!(ch1.contains_key(&1) && !ch1.contains_key(&3));
+        assert_eq!(ch1.len(), 3);
+        assert!(!ch1.is_empty());
+        assert!(ChainMap::<i32, char>::new().extend().is_empty());
+        let mut pairs = ch1.iter().collect::<Vec<_>>();
+        pairs.sort_unstable();
+        assert_eq!(pairs, vec![(0, 'c'), (1, 'b'), (2, 'd')]);
+        ch1.retain(|k, _| *k != 0);
+        assert_eq!(ch1.get(&0), Some('a'));
+        assert_eq!(ch1.get(&2), Some('d'));
+    }
+
+    #[test]
+    fn entry() {
+        let ch0 = ChainMap::new_with(map![0 => 1]);
+        let mut ch1 = ch0.extend();
+        *ch1.entry(0).or_insert(10) += 1;
+        *ch1.entry(0).or_insert(10) += 1;
+        ch1.entry(1).and_modify(|v| *v += 1).or_default();
+        ch1.entry(1).and_modify(|v| *v += 1).or_default();
+        assert!(ch1.entry(1).is_occupied());
+        assert!(!ch1.entry(2).is_occupied());
+        assert_eq!(ch0.get(&0), Some(1));
+        assert_eq!(ch1.get(&0), Some(12));
+        assert_eq!(ch1.get(&1), Some(1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn entry_despite_lock() {
+        let mut ch = ChainMap::<i32, i32>::new().locked();
+        ch.entry(0).or_insert(0);
+    }
+
+    #[test]
+    fn map_like() {
+        fn fill<M: MapLike<i32, char>>(m: &mut M) -> Option<char> {
+            m.insert(0, 'a');
+            m.insert(1, 'b');
+            m.retain(|k, _| *k != 1);
+            *m.entry(3).or_insert('e') = 'f';
+            assert_eq!(*m.entry(3).or_insert_with(|| 'g'), 'f');
+            assert_eq!(m.iter().filter(|(_, v)| *v == 'f').count(), 1);
+            m.insert(0, 'c')
+        }
+        let mut h = HashMap::new();
+        let mut ch = ChainMap::new_with(map![2 => 'd']).extend();
+        assert_eq!(fill(&mut h), Some('a'));
+        assert_eq!(fill(&mut ch), Some('a'));
+        assert_eq!(MapLike::len(&h), 2);
+        assert_eq!(MapLike::len(&ch), 3);
+        assert_eq!(MapLike::get_key_value(&ch, &2), Some((2, 'd')));
+    }
+
+    #[test]
+    fn debug() {
+        let ch = ChainMap::new_with(map![0 => 'a']).extend_with(map![0 => 'b']);
+        assert_eq!(format!("{:?}", ch), "{0: 'b'}");
+    }
+
+    #[test]
+    fn collect_into() {
+        let ch = ChainMap::new_with(map![0 => 'a', 3 => 'd'])
+            .extend_with(map![1 => 'b', 2 => 'c'])
+            .extend_with(map![0 => 'e']);
+        let mut out = map![4 => 'f'];
+        ch.collect_into(&mut out);
+        assert_eq!(out, map![0 => 'e', 1 => 'b', 2 => 'c', 3 => 'd']);
+        ChainMap::new().collect_into(&mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn collect_cached() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_with(map![1 => 'b']);
+        let first = ch1.collect_cached();
+        assert!(Arc::ptr_eq(&first, &ch1.collect_cached()));
+        assert_eq!(*first, map![0 => 'a', 1 => 'b']);
+        ch0.update(&0, 'c');
+        let second = ch1.collect_cached();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(*second, map![0 => 'c', 1 => 'b']);
+        // Forking leaves the contents unchanged until the next insertion
+        let _ = ch1.fork();
+        assert!(Arc::ptr_eq(&second, &ch1.collect_cached()));
+        ch1.insert(2, 'd');
+        assert_eq!(ch1.collect_cached().get(&2), Some(&'d'));
+        ch1.entry(2).or_insert('e');
+        ch1.retain(|_, _| true);
+        assert_eq!(*ch1.collect_cached(), ch1.collect());
+    }
+
+    #[test]
+    fn is_effectively_writable() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let ch1 = ch0.extend_with(map![1 => 'b']).locked();
+        let ch2 = ch1.extend_with(map![2 => 'c']);
+        assert!(ch2.is_effectively_writable(&0));
+        assert!(!ch2.is_effectively_writable(&1));
+        assert!(ch2.is_effectively_writable(&2));
+        assert!(!ch2.is_effectively_writable(&3));
+        let ch3 = ch2.extend().readonly();
+        assert!(!ch3.is_effectively_writable(&0));
+        assert!(!ch3.is_effectively_writable(&2));
+    }
+
+    #[test]
+    fn classify() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_with(map![1 => 'b']);
+        let mut ch2 = ch1.fork_with(map![2 => 'c']);
+        ch1.insert(3, 'd');
+        let ch3 = ch2.fork();
+        ch2.insert(4, 'e');
+        assert_eq!(ch0.classify(&0), Binding::Local);
+        assert_eq!(ch1.classify(&0), Binding::Global);
+        assert_eq!(ch1.classify(&1), Binding::Local);
+        assert_eq!(ch1.classify(&3), Binding::Local);
+        assert_eq!(ch2.classify(&2), Binding::Local);
+        assert_eq!(ch2.classify(&4), Binding::Local);
+        assert_eq!(ch2.classify(&1), Binding::Captured { depth: 1 });
+        assert_eq!(ch2.classify(&3), Binding::Unbound);
+        assert_eq!(ch3.classify(&2), Binding::Captured { depth: 1 });
+        assert_eq!(ch3.classify(&1), Binding::Captured { depth: 2 });
+        assert_eq!(ch3.classify(&0), Binding::Global);
+    }
+
+    #[test]
+    fn free_variables() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_with(map![1 => 'b']);
+        let ch2 = ch1.fork_with(map![2 => 'c']);
+        assert_eq!(ch2.free_variables(vec![3, 2, 1, 0, 1, 2]), vec![3, 1, 0]);
+        assert_eq!(ch2.free_variables(vec![2]), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn interned() {
+        let mut ch0 = ChainMap::new();
+        let mut ch1 = ch0.extend();
+        ch0.insert_interned(0, String::from("yes"));
+        ch1.insert_interned(1, String::from("yes"));
+        ch1.insert_interned(2, String::from("no"));
+        assert!(Arc::ptr_eq(&ch0.get(&0).unwrap(), &ch1.get(&1).unwrap()));
+        assert!(!Arc::ptr_eq(&ch1.get(&1).unwrap(), &ch1.get(&2).unwrap()));
+        ch1.insert(2, Arc::new(String::from("maybe")));
+        ch1.purge_interned();
+        let no = ch0.intern(String::from("no"));
+        assert_eq!(Arc::strong_count(&no), 2);
+    }
+
+    #[test]
+    fn normalized_keys() {
+        let normalizer = KeyNormalizer::new().trim().lowercase();
+        assert_eq!(normalizer.normalize("  Foo Bar "), "foo bar");
+        assert_eq!(KeyNormalizer::new().normalize(" Foo "), " Foo ");
+        let mut ch0 = NormalizedChainMap::new(normalizer);
+        ch0.insert("Timeout", 10);
+        let mut ch1 = ch0.extend();
+        ch1.update(" TIMEOUT", 20);
+        ch1.insert("retries ", 3);
+        assert_eq!(ch0.get("timeout"), Some(20));
+        assert_eq!(ch1.local_get("Retries"), Some(3));
+        assert!(ch1.contains_key("RETRIES"));
+        assert!(!ch0.contains_key("retries"));
+        assert_eq!(ch1.as_chain().get(&String::from("retries")), Some(3));
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn normalized_keys_nfc() {
+        let mut ch = NormalizedChainMap::new(KeyNormalizer::new().nfc());
+        ch.insert("caf\u{65}\u{301}", 'a');
+        assert_eq!(ch.get("caf\u{e9}"), Some('a'));
+    }
+
+    #[test]
+    fn get_traced() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']).locked();
+        let ch1 = ch0.extend().readonly();
+        let (val, trace) = ch1.get_traced(&0);
+        assert_eq!(val, Some('a'));
+        assert_eq!(trace.resolved_at(), Some(1));
+        assert_eq!(trace.steps()[0].outcome, Outcome::Missing);
+        assert!(trace.steps()[0].readonly && trace.steps()[1].locked);
+        assert_eq!(
+            trace.to_string(),
+            "layer 0: missing [readonly]\nlayer 1: found [locked]\n"
+        );
+        let (val, trace) = ch1.get_traced(&1);
+        assert_eq!(val, None);
+        assert_eq!(trace.resolved_at(), None);
+        assert_eq!(trace.steps().len(), 2);
+    }
+
+    #[test]
+    fn traced_stops() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let ch1 = ch0.fork().readonly();
+        let (val, trace) = ch1.local_get_traced(&0);
+        assert_eq!(val, None);
+        assert_eq!(trace.steps().len(), 1);
+        assert_eq!(trace.steps()[0].outcome, Outcome::ScopeStop);
+        assert_eq!(trace.to_string(), "layer 0: end of scope [readonly]\n");
+        assert_eq!(ch0.local_get_traced(&0).0, Some('a'));
+        let trace = ch1.update_trace(&0);
+        assert_eq!(trace.steps()[0].outcome, Outcome::ReadOnlyStop);
+        assert_eq!(trace.resolved_at(), None);
+        let trace = ch1.extend().update_trace(&0);
+        assert_eq!(trace.steps().len(), 2);
+        assert_eq!(trace.steps()[1].outcome, Outcome::ReadOnlyStop);
+        assert!(ch0.update_trace(&0).resolved_at().is_some());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn chain_backed() {
+        #[derive(ChainBacked)]
+        struct Config {
+            #[chain(timeout: u64, name: String)]
+            env: ChainMap<String, String>,
+        }
+        let mut root = ChainMap::new();
+        root.insert(String::from("timeout"), String::from("30"));
+        let mut cfg = Config { env: root.extend() };
+        assert_eq!(cfg.timeout(), Some(30));
+        assert_eq!(cfg.name(), None);
+        cfg.set_timeout(60);
+        cfg.set_name(String::from("test"));
+        assert_eq!(root.get(&String::from("timeout")), Some(String::from("60")));
+        assert_eq!(cfg.name().as_deref(), Some("test"));
+        cfg.env.insert(String::from("timeout"), String::from("soon"));
+        assert_eq!(cfg.timeout(), None);
+    }
+
+    #[test]
+    fn log_layer() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_log();
+        let mut ch2 = ch1.extend();
+        ch1.insert(1, 'b');
+        ch1.insert(1, 'c');
+        ch2.update(&1, 'd');
+        ch2.update(&0, 'e');
+        ch2.update_or(&1, 'f');
+        assert_eq!(ch2.get(&1), Some('f'));
+        assert_eq!(ch2.history(&1), vec!['b', 'c', 'd', 'f']);
+        assert_eq!(ch2.history(&0), vec!['e']);
+        assert_eq!(ch2.history(&2), Vec::<char>::new());
+        assert_eq!(ch1.clone().history(&1), vec!['b', 'c', 'd', 'f']);
+    }
+
+    #[test]
+    fn mark_and_collect() {
+        // Each value is the list of keys it references
+        let ch0 = ChainMap::new_with(map![0 => vec![], 1 => vec![0], 2 => vec![]]);
+        let ch1 = ch0.extend_with(map![3 => vec![], 4 => vec![]]).locked();
+        let mut ch2 = ch1.extend_with(map![5 => vec![1], 6 => vec![6], 2 => vec![]]);
+        let mut removed = ch2.mark_and_collect(vec![5], |v: &Vec<i32>, mark| {
+            for k in v {
+                mark(k);
+            }
+        });
+        removed.sort_unstable();
+        assert_eq!(removed, vec![2, 6]);
+        assert_eq!(ch2.get(&2), Some(vec![]));
+        assert!(ch2.contains_key(&0) && ch2.contains_key(&1) && ch2.contains_key(&5));
+        assert!(ch2.contains_key(&3) && ch2.contains_key(&4));
+    }
+
+    #[test]
+    fn fork_is_lazy() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let ch1 = ch0.fork();
+        assert_eq!(ch0.generations().len(), 1);
+        assert_eq!(ch0.clone().generations().len(), 1);
+        let ch2 = ch0.fork();
+        assert_eq!(ch0.generations().len(), 1);
+        ch0.update(&0, 'b');
+        assert_eq!(ch0.generations().len(), 1);
+        ch0.insert(1, 'c');
+        assert_eq!(ch0.generations().len(), 2);
+        assert_eq!(ch1.get(&0), Some('b'));
+        assert_eq!(ch2.get(&1), None);
+        assert_eq!(ch0.local_get(&0), Some('b'));
+    }
+
+    #[test]
+    fn fork_pending_reads() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']).locked();
+        let _ch1 = ch0.fork();
+        assert!(ch0.is_unlocked() && !ch0.is_locked() && ch0.is_fallthrough());
+        assert_eq!(ch0.generations().len(), 1);
+        let before = (
+            ch0.flags_for(&0),
+            ch0.get_within(&0, 0),
+            ch0.get_traced(&0).1,
+            ch0.validate(),
+        );
+        assert_eq!(before.0.map(|flags| flags.depth), Some(1));
+        assert_eq!(before.1, Err(DepthExceeded { max_depth: 0 }));
+        assert_eq!(ch0.generations().len(), 1);
+        ch0.insert(1, 'b');
+        assert_eq!(ch0.generations().len(), 2);
+        let after = (
+            ch0.flags_for(&0),
+            ch0.get_within(&0, 0),
+            ch0.get_traced(&0).1,
+            ch0.validate(),
+        );
+        assert_eq!(before, after);
+        assert_eq!(ch0.iter_layers().next().map(|layer| layer.depth), Some(0));
+    }
+
+    #[test]
+    fn extend_after_fork() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']).locked();
+        let ch1 = ch0.fork();
+        let ch2 = ch0.extend();
+        assert!(ch0.is_unlocked());
+        ch0.insert(1, 'b');
+        assert_eq!(ch2.get(&1), Some('b'));
+        assert_eq!(ch1.get(&1), None);
+        let mut ch3 = ch0.clone();
+        let _ = ch3.fork();
+        ch3.insert(2, 'c');
+        assert_eq!(ch3.get(&1), Some('b'));
+        assert_eq!(ch0.get(&2), None);
+    }
+
+    #[test]
+    fn set_fallthrough() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.fork();
+        assert!(ch0.is_fallthrough());
+        assert!(!ch1.is_fallthrough());
+        assert_eq!(ch1.local_get(&0), None);
+        ch1.set_fallthrough(true);
+        assert_eq!(ch1.local_get(&0), Some('a'));
+        ch0.set_fallthrough(false);
+        assert_eq!(ch0.local_get(&0), None);
+    }
+
+    #[test]
+    fn local_get_fallthrough_root() {
+        let mut root = ChainMap::new_with(map![0 => 'a']);
+        root.set_fallthrough(true);
+        assert_eq!(root.local_get(&0), Some('a'));
+        assert_eq!(root.local_get(&1), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_fallthrough_shared() {
+        let mut ch0 = ChainMap::<i32, char>::new();
+        let _ch1 = ch0.extend();
+        ch0.set_fallthrough(true);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn from_json_layers() {
+        let doc = serde_json::json!([{"a": 1, "b": 2}, {"b": 3}, {"c": 4}]);
+        let ch = ChainMap::<String, i32>::from_json_layers(doc).unwrap();
+        assert_eq!(ch.get(&String::from("a")), Some(1));
+        assert_eq!(ch.get(&String::from("b")), Some(3));
+        assert_eq!(ch.local_get(&String::from("b")), None);
+        assert_eq!(ch.local_get(&String::from("c")), Some(4));
+        assert!(ChainMap::<String, i32>::from_json_layers(serde_json::json!([]))
+            .unwrap()
+            .is_empty());
+        assert!(ChainMap::<String, i32>::from_json_layers(serde_json::json!({})).is_err());
+        assert!(ChainMap::<String, i32>::from_json_layers(serde_json::json!([1])).is_err());
+        assert!(ChainMap::<String, i32>::from_json_layers(serde_json::json!([{"a": "x"}])).is_err());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn to_json_with_provenance() {
+        let ch = ChainMap::new_with(map![String::from("a") => 1, String::from("b") => 2])
+            .extend_with(map![String::from("b") => 3]);
+        assert_eq!(
+            ch.to_json_with_provenance().unwrap(),
+            serde_json::json!({
+                "a": {"value": 1, "depth": 1},
+                "b": {"value": 3, "depth": 0},
+            })
+        );
+    }
+
+    #[test]
+    fn vecmap() {
+        let mut ch0 = ChainVecMap::new_with(vec![Some('a'), None, Some('b')]);
+        let mut ch1 = ch0.extend();
+        let ch2 = ch1.fork_with(vec![None, Some('c')]);
+        ch1.insert(3, 'd');
+        ch0.update(0, 'e');
+        assert_eq!(ch2.get(0), Some('e'));
+        assert_eq!(ch2.get(1), Some('c'));
+        assert_eq!(ch2.get(3), None);
+        assert_eq!(ch1.get(3), Some('d'));
+        assert_eq!(ch1.local_get(3), Some('d'));
+        assert_eq!(ch1.local_get(2), None);
+        assert_eq!(ch2.local_get(0), None);
+        assert_eq!(ch1.collect(), vec![Some('e'), None, Some('b'), Some('d')]);
+        let mut ch3 = ch1.extend().readonly().extend();
+        ch3.update_or(0, 'f');
+        assert_eq!(ch3.get(0), Some('f'));
+        assert_eq!(ch1.get(0), Some('e'));
+    }
+
+    #[test]
+    #[should_panic]
+    fn vecmap_update_despite_lock() {
+        let mut ch = ChainVecMap::new_with(vec![Some('a')]).locked();
+        ch.update(0, 'b');
+    }
+
+    #[test]
+    fn finish() {
+        let ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let frozen = ch0.extend_with(map![1 => 'c']).finish();
+        assert_eq!(frozen.get(&0), Some(&'a'));
+        assert_eq!(frozen.get(&1), Some(&'c'));
+        assert_eq!(frozen.len(), 2);
+        assert!(frozen.contains_key(&0) && !frozen.contains_key(&2));
+        let shared = Arc::clone(&frozen);
+        let handle = std::thread::spawn(move || shared.get(&1).copied());
+        assert_eq!(handle.join().unwrap(), Some('c'));
+    }
+
+    #[test]
+    fn iter_resumable() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch1 = ch0.extend_with(map![0 => 'c']);
+        let mut it = ch1.iter_resumable();
+        assert_eq!(it.next(), Some((0, 'c')));
+        // The toplevel has already been copied, the root not yet
+        ch1.insert(2, 'd');
+        ch0.insert(3, 'e');
+        ch0.update(&1, 'f');
+        let mut rest = it.by_ref().collect::<Vec<_>>();
+        rest.sort_unstable();
+        assert_eq!(rest, vec![(1, 'f'), (3, 'e')]);
+        assert!(it.is_finished());
+    }
+
+    #[test]
+    fn flags_for() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']).locked();
+        let mut ch1 = ch0.fork_with(map![1 => 'b']).readonly();
+        ch0.insert(2, 'c');
+        assert_eq!(
+            ch1.flags_for(&0),
+            Some(KeyFlags {
+                depth: 1,
+                locked: true,
+                readonly: false,
+                fallthrough: false,
+            })
+        );
+        assert_eq!(
+            ch0.flags_for(&2),
+            Some(KeyFlags {
+                depth: 0,
+                locked: false,
+                readonly: false,
+                fallthrough: true,
+            })
+        );
+        assert!(ch1.flags_for(&1).unwrap().readonly);
+        assert_eq!(ch1.flags_for(&2), None);
+        ch1.lock();
+        assert!(ch1.flags_for(&1).unwrap().locked);
+    }
+
+    #[test]
+    fn insert_all() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_with(map![1 => 'b']);
+        let mut overwritten = ch1.insert_all(map![0 => 'c', 1 => 'd', 2 => 'e']);
+        overwritten.sort_unstable();
+        assert_eq!(overwritten, vec![1]);
+        assert_eq!(ch1.get(&0), Some('c'));
+        assert_eq!(ch1.get(&1), Some('d'));
+        assert_eq!(ch0.get(&0), Some('a'));
+        assert_eq!(ch1.insert_all(HashMap::new()), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn extend_cache() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch1 = ch0.extend_cache();
+        let ch2 = ch1.extend();
+        assert_eq!(ch1.cache_stats(), Some(CacheStats::default()));
+        assert_eq!(ch2.cache_stats(), None);
+        assert_eq!(ch2.get(&0), Some('a'));
+        assert_eq!(ch2.get(&0), Some('a'));
+        assert_eq!(ch1.get(&2), None);
+        assert_eq!(ch1.cache_stats(), Some(CacheStats { hits: 1, misses: 2 }));
+        assert_eq!(ch1.local_get(&0), None);
+        // Modifications through other handles make the copy stale
+        ch0.update(&0, 'c');
+        assert_eq!(ch1.get(&0), Some('c'));
+        assert_eq!(ch1.get(&0), Some('c'));
+        assert_eq!(ch1.cache_stats(), Some(CacheStats { hits: 2, misses: 3 }));
+        ch1.invalidate(&0);
+        assert_eq!(ch1.get(&0), Some('c'));
+        // Updates through the cache reach the owner
+        ch1.update(&0, 'd');
+        assert_eq!(ch0.get(&0), Some('d'));
+        assert_eq!(ch1.get(&0), Some('d'));
+    }
+
+    #[test]
+    fn extend_cache_stale() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend();
+        let ch2 = ch1.extend_cache();
+        let ch3 = ch2.extend().extend_cache();
+        assert_eq!(ch3.get(&0), Some('a'));
+        assert_eq!(ch3.get(&0), Some('a'));
+        ch1.insert(0, 'b');
+        assert_eq!(ch3.get(&0), Some('b'));
+        assert_eq!(ch2.get(&0), Some('b'));
+        ch1.remove(&0);
+        ch0.update(&0, 'c');
+        // ch3 now copies from the cache of ch2, and still depends on ch0
+        assert_eq!(ch2.get(&0), Some('c'));
+        assert_eq!(ch3.get(&0), Some('c'));
+        assert_eq!(ch2.cache_stats(), Some(CacheStats { hits: 2, misses: 3 }));
+        ch0.update(&0, 'd');
+        assert_eq!(ch3.get(&0), Some('d'));
+        assert_eq!(ch3.cache_stats(), Some(CacheStats { hits: 1, misses: 4 }));
+    }
+
+    #[test]
+    fn start_snapshotting() {
+        use std::cell::RefCell;
+        use std::time::Duration;
+        let taken = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&taken);
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut snapshotter = ch0.start_snapshotting(Duration::from_secs(3600), move |snap| {
+            sink.borrow_mut().push(snap.bindings)
+        });
+        assert!(!snapshotter.tick());
+        ch0.insert(1, 'b');
+        snapshotter.snapshot_now();
+        let mut snapshotter = ch0.start_snapshotting(Duration::from_secs(0), |_| ());
+        assert!(snapshotter.tick());
+        assert_eq!(*taken.borrow(), vec![map![0 => 'a', 1 => 'b']]);
+    }
+
+    #[test]
+    fn str_keys() {
+        let mut ch0: ChainMap<Arc<str>, i32> = ChainMap::new();
+        ch0.insert_str("a", 1);
+        let mut ch1 = ch0.extend();
+        ch1.insert_str("a", 2);
+        ch1.insert_str("b", 3);
+        assert_eq!(ch1.get_str("a"), Some(2));
+        assert_eq!(ch0.get_str("a"), Some(1));
+        assert_eq!(ch0.get_str("b"), None);
+        let k0 = ch0.get_key_value(&Arc::from("a")).unwrap().0;
+        let k1 = ch1.get_key_value(&Arc::from("a")).unwrap().0;
+        assert!(Arc::ptr_eq(&k0, &k1));
+    }
+
+    #[test]
+    fn entry_or_copy_up() {
+        let ch0 = ChainMap::new_with(map![0 => vec![1]]);
+        let mut ch1 = ch0.extend_with(map![1 => vec![2]]);
+        ch1.entry(0).or_copy_up().unwrap().push(3);
+        assert_eq!(ch1.local_get(&0), Some(vec![1, 3]));
+        assert_eq!(ch0.get(&0), Some(vec![1]));
+        ch1.entry(1).or_copy_up().unwrap().push(4);
+        assert_eq!(ch1.get(&1), Some(vec![2, 4]));
+        assert!(ch1.entry(2).or_copy_up().is_none());
+        assert!(!ch1.entry(2).is_occupied());
+    }
+
+    #[test]
+    fn update_or_report() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let ch1 = ch0.extend_with(map![2 => 'c']).locked();
+        let mut ch2 = ch1.extend().readonly();
+        let mut ch3 = ch2.extend();
+        assert_eq!(ch0.update_or_report(&0, 'd'), UpdateOutcome::Updated);
+        assert_eq!(ch3.update_or_report(&2, 'e'), UpdateOutcome::ShadowedDueToReadonly);
+        assert_eq!(ch2.update_or_report(&2, 'f'), UpdateOutcome::ShadowedDueToReadonly);
+        assert_eq!(ch3.update_or_report(&3, 'g'), UpdateOutcome::InsertedFresh);
+        let mut ch4 = ch1.extend();
+        assert_eq!(ch4.update_or_report(&2, 'h'), UpdateOutcome::ShadowedDueToLock);
+        assert_eq!(ch4.update_or_report(&1, 'i'), UpdateOutcome::Updated);
+        assert_eq!(ch0.get(&1), Some('i'));
+        assert_eq!(ch1.get(&2), Some('c'));
+        assert_eq!(ch4.local_get(&2), Some('h'));
+    }
+
+    #[test]
+    fn validate() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.fork().extend_cache();
+        ch0.insert(1, 'b');
+        assert_eq!(ch1.get(&0), Some('a'));
+        ch1.insert(0, 'c');
+        assert_eq!(ch0.validate(), vec![]);
+        assert_eq!(ch1.validate(), vec![]);
+        let mut ch2 = ch0.extend_cache();
+        assert_eq!(ch2.get(&1), Some('b'));
+        ch2.entry(1).or_insert('d');
+        assert_eq!(ch2.validate(), vec![Issue::CachedOwnBinding { depth: 0 }]);
+        let mut root = ChainMap::<i32, char>::new();
+        root.set_fallthrough(true);
+        assert_eq!(root.validate(), vec![Issue::FallthroughRoot { depth: 0 }]);
+    }
+
+    #[test]
+    fn validate_tombstones() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut ch = root.extend_with(map![1 => 'b']);
+        ch.hide(0);
+        assert_eq!(ch.validate(), vec![]);
+        ch.link().tombstones.lock_recover().insert(1);
+        assert_eq!(ch.validate(), vec![Issue::HiddenOwnBinding { depth: 0 }]);
+        ch.link().hiding.store(false, Ordering::Relaxed);
+        assert_eq!(
+            ch.validate(),
+            vec![
+                Issue::UnmarkedTombstones { depth: 0 },
+                Issue::HiddenOwnBinding { depth: 0 }
+            ]
+        );
+    }
+
+    #[test]
+    fn rename_key() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let ch1 = ch0.extend_with(map![0 => 'c']).locked();
+        let mut ch2 = ch1.extend_with(map![0 => 'd']);
+        assert_eq!(ch2.rename_key(&0, 2, false), 1);
+        assert_eq!(ch2.local_get(&2), Some('d'));
+        assert_eq!(ch2.get(&0), Some('c'));
+        assert_eq!(ch2.rename_key(&0, 3, false), 0);
+        assert_eq!(ch2.rename_key(&0, 3, true), 1);
+        assert_eq!(ch0.get(&3), Some('a'));
+        assert_eq!(ch0.get(&0), None);
+        assert_eq!(ch2.get(&0), Some('c'));
+        assert_eq!(ch0.rename_key(&4, 5, true), 0);
+    }
+
+    #[test]
+    fn collect_btree() {
+        let ch0 = ChainMap::new_with(map![2 => 'a', 0 => 'b']);
+        let ch1 = ch0.extend_with(map![1 => 'c', 2 => 'd']);
+        assert_eq!(
+            ch1.collect_btree().into_iter().collect::<Vec<_>>(),
+            vec![(0, 'b'), (1, 'c'), (2, 'd')]
+        );
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn collect_index() {
+        let ch0 = ChainMap::new_with(map![2 => 'a', 0 => 'b']);
+        let ch1 = ch0.extend_with(map![1 => 'c']);
+        let map = ch1.collect_index();
+        assert_eq!(map.get_index(0), Some((&1, &'c')));
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&2), Some(&'a'));
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn sample() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let ch0 = ChainMap::new_with((0..100).map(|i| (i, 0)).collect());
+        let ch1 = ch0.extend_with((0..50).map(|i| (i, 1)).collect());
+        let picked = ch1.sample(30, &mut rng);
+        assert_eq!(picked.len(), 30);
+        for (k, v) in &picked {
+            assert_eq!(ch1.get(k), Some(*v));
+        }
+        assert_eq!(picked.iter().map(|(k, _)| k).collect::<HashSet<_>>().len(), 30);
+        assert_eq!(ch1.sample(200, &mut rng).len(), 100);
+    }
+
+    #[test]
+    fn local_overrides() {
+        let ch0 = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let ch1 = ch0.extend_with(map![1 => 'c']);
+        let ch2 = ch1.extend_with(map![0 => 'd', 1 => 'e', 2 => 'f']);
+        let mut overrides = ch2.local_overrides().collect::<Vec<_>>();
+        overrides.sort_unstable();
+        assert_eq!(
+            overrides,
+            vec![(0, 'd', Some('a')), (1, 'e', Some('c')), (2, 'f', None)]
+        );
+        assert_eq!(ch0.local_overrides().count(), 2);
+    }
+
+    #[test]
+    fn fixture() {
+        let ch = ChainMap::fixture(4, 10, 42);
+        assert_eq!(ch.collect(), ChainMap::fixture(4, 10, 42).collect());
+        assert_ne!(ch.collect(), ChainMap::fixture(4, 10, 43).collect());
+        assert_eq!(ch.local_overrides().count(), 10);
+        let (k, v) = ch.local_overrides().next().map(|(k, v, _)| (k, v)).unwrap();
+        assert_resolves!(ch, k => v @ 0);
+        assert_resolves!(ch, k => v);
+        assert_eq!(ChainMap::fixture(0, 0, 0).len(), 0);
+    }
+
+    #[test]
+    fn as_flat_mut() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend();
+        {
+            let mut flat = ch1.as_flat_mut();
+            assert_eq!(flat.insert(1, 'b'), None);
+            assert_eq!(flat.insert(0, 'c'), None);
+            assert_eq!(flat.remove(&1), Some('b'));
+            assert_eq!(flat.iter().count(), 1);
+        }
+        assert_eq!(ch1.get(&0), Some('c'));
+        assert_eq!(ch1.get(&1), None);
+        assert_eq!(ch0.get(&0), Some('a'));
+    }
+
+    #[test]
+    #[should_panic]
+    fn as_flat_mut_locked() {
+        let mut ch = ChainMap::<i32, i32>::new().locked();
+        ch.as_flat_mut();
+    }
+
+    #[test]
+    fn snapshotting_with_clock() {
+        use std::cell::Cell;
+        use std::time::Duration;
+        let clock = Rc::new(MockClock::new());
+        let count = Rc::new(Cell::new(0));
+        let sink = Rc::clone(&count);
+        let ch = ChainMap::new_with(map![0 => 'a']);
+        let mut snapshotter = ch.start_snapshotting_with_clock(
+            Duration::from_secs(10),
+            Rc::clone(&clock),
+            move |_| sink.set(sink.get() + 1),
+        );
+        assert!(!snapshotter.tick());
+        clock.advance(Duration::from_secs(9));
+        assert!(!snapshotter.tick());
+        clock.advance(Duration::from_secs(1));
+        assert!(snapshotter.tick());
+        assert!(!snapshotter.tick());
+        assert_eq!(count.get(), 1);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn to_json_layers() {
+        let json = serde_json::json!([{"a": 1, "b": 2}, {"a": 3}, {"a": 4, "c": 5}]);
+        let ch = ChainMap::<String, i32>::from_json_layers(json.clone()).unwrap();
+        assert_eq!(ch.to_json_layers(false).unwrap(), json);
+        let compact = ch.to_json_layers(true).unwrap();
+        assert_eq!(compact, serde_json::json!([{"b": 2}, {}, {"a": 4, "c": 5}]));
+        let ch = ChainMap::<String, i32>::from_json_layers(compact).unwrap();
+        assert_eq!(ch.get(&String::from("a")), Some(4));
+        assert_eq!(ch.get(&String::from("b")), Some(2));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn to_json_hidden() {
+        let root = ChainMap::new_with(map![String::from("x") => 1, String::from("y") => 2]);
+        let mut ch = root.extend();
+        ch.hide(String::from("x"));
+        let compact = ch.to_json_layers(true).unwrap();
+        assert_eq!(compact, serde_json::json!([{"y": 2}, {}]));
+        let ch2 = ChainMap::<String, i32>::from_json_layers(compact).unwrap();
+        assert_eq!(ch2.get("x"), None);
+        assert_eq!(
+            ch.to_json_with_provenance().unwrap(),
+            serde_json::json!({"y": {"value": 2, "depth": 1}})
+        );
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_chain() {
+        let mut root = ChainMap::new();
+        root.insert(String::from("a"), String::from("x"));
+        let host = FfiChain::new(root.locked());
+        let mut plugin = host.extend();
+        assert!(plugin.insert("b", "y"));
+        assert_eq!(plugin.get("a").as_deref(), Some("x"));
+        assert_eq!(plugin.get("b").as_deref(), Some("y"));
+        assert_eq!(host.get("b"), None);
+        let mut host = host;
+        assert!(!host.insert("c", "z"));
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_chain_quota() {
+        let mut root = ChainMap::new();
+        root.set_quota(Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        });
+        let mut host = FfiChain::new(root);
+        assert!(host.insert("a", "x"));
+        assert!(host.insert("a", "y"));
+        assert!(!host.insert("b", "z"));
+        assert_eq!(host.get("a").as_deref(), Some("y"));
+        assert_eq!(host.get("b"), None);
+    }
+
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn ffi_chain_panic() {
+        let root = ChainMap::<String, String>::new();
+        root.on_drop(|_| panic!("hook"));
+        let host = FfiChain::new(root);
+        let plugin = host.extend();
+        drop(host);
+        // The panic of the hook stays on the side of the library
+        drop(plugin);
+    }
+
+    #[test]
+    fn derive_view() {
+        let mut ch0 = ChainMap::new_with(map![1 => "one", 2 => "two"]);
+        let mut ch1 = ch0.extend_with(map![3 => "three"]);
+        let view = ch1.derive_view(|k, v| if k % 2 == 1 { Some((v.len(), *k)) } else { None });
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.get(&5), Some(3));
+        let before = view.snapshot();
+        assert!(Arc::ptr_eq(&before, &view.snapshot()));
+        ch1.insert(5, "five");
+        ch0.update(&1, "un");
+        assert_eq!(view.get(&4), Some(5));
+        assert_eq!(view.get(&2), Some(1));
+        assert!(!view.contains_key(&3));
+    }
+
+    #[test]
+    fn quota() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut tenant = root.extend();
+        tenant.set_quota(Quota {
+            max_keys: Some(3),
+            max_bytes: None,
+        });
+        tenant.insert(0, 'c');
+        let mut script = tenant.extend();
+        script.insert(1, 'd');
+        script.insert(2, 'e');
+        assert_eq!(script.try_insert(2, 'f'), Ok(Some('e')));
+        assert_eq!(
+            script.try_insert(3, 'g'),
+            Err(ChainMapError::Quota(QuotaExceeded {
+                quota: tenant.quota().unwrap(),
+                keys: 4,
+                bytes: 4 * 8,
+            }))
+        );
+        let mut sibling = tenant.clone();
+        assert!(sibling.try_insert(1, 'h').is_ok());
+        assert!(sibling.try_insert(2, 'i').is_ok());
+        assert!(sibling.try_insert(3, 'j').is_err());
+        script.clear_quota();
+        script.insert(3, 'g');
+    }
+
+    #[test]
+    #[should_panic]
+    fn quota_insert() {
+        let mut ch = ChainMap::new();
+        ch.set_quota(Quota {
+            max_keys: None,
+            max_bytes: Some(16),
+        });
+        ch.insert(0u64, 0u64);
+        ch.insert(1u64, 0u64);
+    }
+
+    #[test]
+    fn quota_entry() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let mut ch = ChainMap::new();
+        ch.set_quota(Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        });
+        *ch.entry(0).or_insert('a') = 'b';
+        *ch.entry(0).or_insert('c') = 'd';
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            ch.entry(1).or_insert('e');
+        }));
+        assert!(res.is_err());
+        assert_eq!(ch.collect(), map![0 => 'd']);
+    }
+
+    #[test]
+    #[should_panic]
+    fn quota_as_flat_mut() {
+        let mut ch = ChainMap::new();
+        ch.set_quota(Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        });
+        ch.insert(0, 'a');
+        ch.as_flat_mut().insert(1, 'b');
+    }
+
+    #[test]
+    fn batch() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend_with(map![1 => 'b']);
+        let len = ch1.batch(|layer| {
+            layer.insert(2, 'c');
+            layer.remove(&1);
+            layer.len()
+        });
+        assert_eq!(len, 1);
+        assert_eq!(ch1.collect(), map![0 => 'a', 2 => 'c']);
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            ch1.batch(|layer| {
+                layer.insert(3, 'd');
+                panic!("in batch");
+            })
+        }));
+        assert!(res.is_err());
+        assert!(ch1.is_consistent());
+        assert_eq!(ch1.get(&3), None);
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            *ch1.entry(3).or_insert('d') = 'e';
+            let _guard = ch1.entry(4);
+            panic!("in entry");
+        }));
+        assert!(res.is_err());
+        assert!(!ch1.is_consistent());
+        assert_eq!(ch1.validate(), vec![Issue::Poisoned { depth: 0 }]);
+    }
+
+    #[test]
+    fn debug_scopes() {
+        let mut global = ChainMap::new_with(map!["x" => 1, "y" => 2]);
+        let mut closure = global.fork_with(map!["x" => 3]);
+        let local = closure.fork_with(map!["z" => 4]);
+        closure.insert("w", 5);
+        let scopes = local.debug_scopes();
+        let var = |name: &str, value: &str, shadowed| DebugVariable {
+            name: name.to_string(),
+            value: value.to_string(),
+            shadowed,
+        };
+        assert_eq!(
+            scopes,
+            vec![
+                DebugScope {
+                    name: String::from("Local"),
+                    kind: ScopeKind::Local,
+                    variables: vec![var("\"z\"", "4", false)],
+                },
+                DebugScope {
+                    name: String::from("Closure 1"),
+                    kind: ScopeKind::Closure,
+                    variables: vec![var("\"x\"", "3", false)],
+                },
+                DebugScope {
+                    name: String::from("Global"),
+                    kind: ScopeKind::Global,
+                    variables: vec![var("\"x\"", "1", true), var("\"y\"", "2", false)],
+                },
+            ]
+        );
+        global.insert("v", 6);
+        assert_eq!(global.debug_scopes().len(), 1);
+        assert_eq!(closure.debug_scopes()[0].variables.len(), 2);
+    }
+
+    #[test]
+    fn collect_sorted_by() {
+        let ch0 = ChainMap::new_with(map![("net", "port") => 1, ("app", "name") => 2]);
+        let ch1 = ch0.extend_with(map![("net", "host") => 3, ("app", "name") => 4]);
+        let sorted = ch1.collect_sorted_by(|a, b| a.0.cmp(b.0).then(b.1.cmp(a.1)));
+        assert_eq!(
+            sorted,
+            vec![(("app", "name"), 4), (("net", "port"), 1), (("net", "host"), 3)]
+        );
+    }
+
+    #[test]
+    fn write_up_promotion() {
+        let proto = ChainMap::new_with(map!["x" => 1]);
+        let obj = proto.extend().readonly();
+        let mut instance = obj.extend();
+        assert!(!instance.has_write_up_promotion());
+        instance.set_write_up_promotion(true);
+        assert!(proto.has_write_up_promotion());
+        instance.update(&"x", 2);
+        assert_eq!(instance.local_get(&"x"), Some(2));
+        assert_eq!(proto.get(&"x"), Some(1));
+        instance.update(&"x", 3);
+        assert_eq!(instance.get(&"x"), Some(3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn write_up_promotion_missing() {
+        let proto = ChainMap::new_with(map!["x" => 1]);
+        let mut instance = proto.extend().readonly().extend();
+        instance.set_write_up_promotion(true);
+        instance.update(&"y", 2);
+    }
+
+    #[test]
+    fn clone_variants() {
+        let mut ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.fork();
+        ch0.insert(1, 'b');
+        assert!(ch0.is_fallthrough());
+        let mut copy = ch0.clone();
+        let mut branch = ch0.clone_as_branch();
+        let mut linked = ch0.clone_linked();
+        assert!(copy.is_fallthrough());
+        assert!(!branch.is_fallthrough());
+        assert!(linked.is_fallthrough());
+        assert_eq!(branch.local_get(&1), Some('b'));
+        assert_eq!(branch.local_get(&0), None);
+        copy.insert(2, 'c');
+        branch.insert(3, 'd');
+        linked.insert(4, 'e');
+        assert_eq!(ch0.get(&2), None);
+        assert_eq!(ch0.get(&3), None);
+        assert_eq!(ch0.get(&4), Some('e'));
+        let _ch2 = ch1.fork();
+        let pending = ch1.clone_as_branch();
+        assert!(!pending.is_fallthrough());
+        ch1.insert(5, 'f');
+        assert_eq!(pending.get(&5), None);
+    }
+
+    #[test]
+    fn empty_hint() {
+        let ch0 = ChainMap::new_with(map![0 => 'a']);
+        let mut ch1 = ch0.extend();
+        assert!(ch1.link().empty.load(Ordering::Relaxed));
+        assert_eq!(ch1.get(&0), Some('a'));
+        ch1.entry(1).or_insert('b');
+        assert!(!ch1.link().empty.load(Ordering::Relaxed));
+        assert_eq!(ch1.get(&1), Some('b'));
+        let mut ch2 = ch1.extend();
+        ch2.batch(|layer| layer.insert(2, 'c'));
+        assert_eq!(ch2.local_get(&2), Some('c'));
+        assert_eq!(ch2.clone().get(&2), Some('c'));
+    }
+
+    #[test]
+    fn chain_diff() {
+        let left = ChainMap::new_with(map![0 => 'a', 1 => 'b', 2 => 'c']);
+        let right = ChainMap::new_with(map![1 => 'b', 3 => 'd']).extend_with(map![2 => 'e']);
+        assert_eq!(
+            testing::chain_diff(&left, &right).unwrap(),
+            "differs:       2 => 'c' (depth 0) in left, 'e' (depth 0) in right\n\
+             only in left:  0 => 'a' (depth 0)\n\
+             only in right: 3 => 'd' (depth 1)"
+        );
+        assert_chain_eq!(left, left.extend());
+    }
+
+    #[test]
+    #[should_panic(expected = "only in right")]
+    fn assert_chain_eq() {
+        let left = ChainMap::<i32, i32>::new();
+        let right = ChainMap::new_with(map![0 => 0]);
+        assert_chain_eq!(left, right);
+    }
+
+    #[test]
+    fn replace_layer_contents() {
+        let remote = ChainMap::new_with(map!["timeout" => 10, "retries" => 3]).locked();
+        let mut local = remote.extend_with(map!["retries" => 5]);
+        let ids = local.layer_ids();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        let snapshot = local.collect_cached();
+        let old = local.replace_layer_contents(ids[1], map!["timeout" => 20]);
+        assert_eq!(old, Some(map!["timeout" => 10, "retries" => 3]));
+        assert_eq!(local.get(&"timeout"), Some(20));
+        assert_eq!(local.get(&"retries"), Some(5));
+        assert!(remote.is_locked());
+        assert!(!Arc::ptr_eq(&snapshot, &local.collect_cached()));
+        assert_eq!(remote.extend().replace_layer_contents(ids[0], HashMap::new()), None);
+    }
+
+    #[test]
+    fn root() {
+        let mut base = ChainMap::new_with(map![0 => 'a']);
+        let mut top = base.fork();
+        top.insert(1, 'b');
+        let mut root = top.root();
+        assert_eq!(root.get(&1), None);
+        root.insert(2, 'c');
+        assert_eq!(top.get(&2), Some('c'));
+        assert_eq!(base.get(&2), Some('c'));
+        assert_eq!(root.root().layer_ids(), root.layer_ids());
+    }
+
+    #[test]
+    fn bulk_load() {
+        let mut base = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        base.set_quota(Quota { max_keys: Some(5), max_bytes: None });
+        let mut ch = base.extend_with(map![2 => 'c']);
+        let options = BulkOptions { presize: true, dedup: true };
+        let stats = ch.bulk_load((0..6).map(|i| (i, (b'a' + i as u8) as char)), options);
+        assert_eq!(
+            stats,
+            BulkStats { inserted: 2, overwritten: 1, deduplicated: 2, rejected: 1 }
+        );
+        assert_eq!(ch.head().lock().unwrap().len(), 3);
+        assert_eq!(ch.get(&4), Some('e'));
+        assert_eq!(ch.get(&5), None);
+        let stats = ch.locked().bulk_load(vec![(9, 'z')], BulkOptions::default());
+        assert_eq!(stats.rejected, 1);
+    }
+
+    #[test]
+    fn bulk_load_hidden() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut ch = root.extend();
+        ch.hide(0);
+        let options = BulkOptions { presize: false, dedup: true };
+        let stats = ch.bulk_load(vec![(0, 'a')], options);
+        assert_eq!(stats.inserted, 1);
+        assert_eq!(ch.get(&0), Some('a'));
+    }
+
+    #[test]
+    fn routing() {
+        let root = ChainMap::new_with(map!["builtin.print" => 0, "builtin.len" => 1]);
+        let mut ch = root.extend().extend().extend_with(map!["user.x" => 2]);
+        ch.set_routing(|k: &&'static str| {
+            let k = *k;
+            k.find('.').map(|i| &k[..i])
+        });
+        assert_eq!(ch.get_routed(&"builtin.len"), Some(1));
+        assert_eq!(ch.get_routed(&"builtin.print"), Some(0));
+        assert_eq!(ch.get_routed(&"builtin.none"), None);
+        assert_eq!(ch.routing_stats(), Some(RoutingStats { skipped: 6, learned: 1 }));
+        // Binding a key of the class higher in the chain invalidates the route
+        ch.insert("builtin.len", 3);
+        assert_eq!(ch.get_routed(&"builtin.len"), Some(3));
+        assert_eq!(ch.get_routed(&"builtin.print"), Some(0));
+        assert_eq!(ch.get_routed(&"other"), None);
+        assert_eq!(ch.routing_stats(), Some(RoutingStats { skipped: 6, learned: 2 }));
+    }
+
+    #[test]
+    fn routing_clone() {
+        let root = ChainMap::new_with(map!["builtin.len" => 1]);
+        let mut ch = root.extend().extend_with(map!["user.x" => 2]);
+        ch.set_routing(|k: &&'static str| {
+            let k = *k;
+            k.find('.').map(|i| &k[..i])
+        });
+        assert_eq!(ch.get_routed(&"builtin.len"), Some(1));
+        let copy = ch.clone();
+        assert!(copy.routing_stats().is_some());
+        assert_eq!(copy.get_routed(&"builtin.len"), Some(1));
+        assert_eq!(copy.get_routed(&"user.x"), Some(2));
+    }
+
+    #[test]
+    fn typed_key() {
+        struct Retries;
+        impl TypedKey for Retries {
+            const NAME: &'static str = "retries";
+            type Value = u32;
+        }
+        let mut root = ChainMap::<Rc<str>, u64>::new();
+        root.insert_typed::<Retries>(3);
+        let mut ch = root.extend();
+        assert_eq!(ch.get_typed::<Retries>(), Some(3));
+        ch.insert(Rc::from("retries"), u64::MAX);
+        assert_eq!(ch.get_typed::<Retries>(), None);
+        assert_eq!(root.get_typed::<Retries>(), Some(3));
+    }
+
+    #[test]
+    fn on_drop() {
+        use std::cell::RefCell;
+        let flushed = Rc::new(RefCell::new(None));
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut session = root.extend();
+        session.insert(1, 'b');
+        let sink = Rc::clone(&flushed);
+        session.on_drop(move |layer| *sink.borrow_mut() = Some(layer));
+        let inner = session.extend();
+        drop(session);
+        assert_eq!(*flushed.borrow(), None);
+        drop(inner);
+        assert_eq!(*flushed.borrow(), Some(map![1 => 'b']));
+    }
+
+    #[test]
+    fn get_within() {
+        let ch = ChainMap::new_with(map![0 => 'a']).extend_with(map![1 => 'b']).extend();
+        assert_eq!(ch.get_within(&1, 1), Ok(Some('b')));
+        assert_eq!(ch.get_within(&0, 1), Err(DepthExceeded { max_depth: 1 }));
+        assert_eq!(ch.get_within(&0, 2), Ok(Some('a')));
+        assert_eq!(ch.get_within(&5, 2), Ok(None));
+        assert_eq!(
+            ch.get_within(&5, 0).unwrap_err().to_string(),
+            "Lookup exceeded maximum depth 0"
+        );
+    }
+
+    #[test]
+    fn view_remapped() {
+        let mut ch = ChainMap::new_with(map![
+            String::from("acme.limit") => 10,
+            String::from("globex.limit") => 20
+        ]);
+        let acme = ch.view_remapped(|k: &String| format!("acme.{}", k));
+        assert_eq!(acme.get("limit"), Some(10));
+        assert!(!acme.contains_key("acme.limit"));
+        ch.insert(String::from("acme.quota"), 5);
+        assert_eq!(acme.get("quota"), Some(5));
+    }
+
+    #[test]
+    fn collect_checked() {
+        let ch = ChainMap::new_with(map!["port" => 80, "host" => 1, "debug" => 0])
+            .extend_with(map!["port" => 8080, "debug" => 0])
+            .extend_with(map!["port" => 80]);
+        let (map, conflicts) = ch.collect_checked();
+        assert_eq!(map, ch.collect());
+        assert_eq!(conflicts, vec![("port", vec![80, 8080, 80])]);
+    }
+
+    #[test]
+    fn remove_and_hide() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b', 2 => 'c']);
+        let mut ch = root.extend_with(map![0 => 'x']);
+        assert_eq!(ch.remove(&0), Some('x'));
+        assert_eq!(ch.remove(&0), None);
+        assert_eq!(ch.get(&0), Some('a'));
+        assert_eq!(ch.hide(1), None);
+        let mut inner = ch.extend();
+        assert_eq!(inner.get(&1), None);
+        assert!(!inner.contains_key(&1));
+        assert_eq!(inner.collect(), map![0 => 'a', 2 => 'c']);
+        assert_eq!(inner.len(), 2);
+        assert_eq!(root.get(&1), Some('b'));
+        inner.insert(1, 'y');
+        assert_eq!(inner.get(&1), Some('y'));
+        assert!(ch.unhide(&1));
+        assert_eq!(ch.get(&1), Some('b'));
+        let scope = ch.fork();
+        ch.hide(2);
+        assert_eq!(ch.local_get(&2), None);
+        assert_eq!(scope.local_get(&2), None);
+        assert_eq!(scope.get(&2), Some('c'));
+    }
+
+    #[test]
+    fn pop() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut ch = root.extend_with(map![0 => 'b', 1 => 'c']);
+        assert_eq!(ch.pop(&0), Some('b'));
+        assert_eq!(ch.get(&0), Some('a'));
+        assert_eq!(ch.pop(&0), None);
+        assert_eq!(ch.pop(&1), Some('c'));
+        assert_eq!(root.get(&0), Some('a'));
+    }
+
+    #[test]
+    fn hidden_keys_everywhere() {
+        let root = ChainMap::new_with(map![1 => 'a']);
+        let mut ch = root.extend_log();
+        ch.hide(1);
+        let (val, trace) = ch.get_traced(&1);
+        assert_eq!(val, None);
+        assert_eq!(trace.steps().len(), 1);
+        assert_eq!(trace.steps()[0].outcome, Outcome::Masked);
+        assert_eq!(trace.to_string(), "layer 0: masked\n");
+        assert!(ch.is_empty());
+        assert_eq!(ch.len(), 0);
+        assert!(ch.collect_checked().0.is_empty());
+        assert!(!ch.is_effectively_writable(&1));
+        assert_eq!(ch.try_update(&1, 'b'), Err(ChainMapError::KeyMissing));
+        assert_eq!(ch.flags_for(&1), None);
+        assert_eq!(ch.classify(&1), Binding::Unbound);
+        assert_eq!(ch.occurrences(&1), 0);
+        assert!(ch.history(&1).is_empty());
+        assert!(ch.entry(1).or_copy_up().is_none());
+        assert!(ch.debug_scopes()[0].variables.is_empty());
+        assert_eq!(root.get(&1), Some('a'));
+        assert!(!root.is_empty());
+        assert_eq!(ch.rename_key(&1, 2, true), 0);
+        let mut top = ch.extend().readonly().extend();
+        assert_eq!(top.update_or_report(&1, 'b'), UpdateOutcome::InsertedFresh);
+        ch.update_or(&1, 'c');
+        assert_eq!(ch.get(&1), Some('c'));
+        assert_eq!(root.get(&1), Some('a'));
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn sample_hidden() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch = root.extend();
+        ch.hide(0);
+        assert_eq!(ch.sample(5, &mut rng), vec![(1, 'b')]);
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn collect_index_hidden() {
+        let root = ChainMap::new_with(map![1 => 'a', 2 => 'b']);
+        let mut ch = root.extend();
+        ch.hide(1);
+        assert_eq!(ch.collect_index().into_iter().collect::<Vec<_>>(), vec![(2, 'b')]);
+    }
+
+    #[test]
+    fn get_mut() {
+        let root = ChainMap::new_with(map![0 => vec![1, 2]]);
+        let ch = root.extend_with(map![1 => vec![]]);
+        ch.get_mut(&0).unwrap().push(3);
+        ch.get_mut(&1).unwrap().push(4);
+        assert_eq!(root.get(&0), Some(vec![1, 2, 3]));
+        assert_eq!(ch.get(&1), Some(vec![4]));
+        assert!(ch.get_mut(&2).is_none());
+        let protected = root.extend().readonly();
+        assert!(protected.extend().get_mut(&0).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Key is locked")]
+    fn get_mut_locked() {
+        let ch = ChainMap::new_with(map![0 => 'a']).locked().extend();
+        ch.get_mut(&0);
+    }
+
+    #[test]
+    fn insert_returns_previous() {
+        let mut ch = ChainMap::new_with(map![0 => 'a']).extend();
+        assert_eq!(ch.insert(0, 'b'), None);
+        assert_eq!(ch.insert(0, 'c'), Some('b'));
+        let mut log = ch.extend_log();
+        log.insert(1, 'x');
+        assert_eq!(log.insert(1, 'y'), Some('x'));
+        assert_eq!(log.history(&1), vec!['x', 'y']);
+    }
+
+    #[test]
+    fn get_key_value_stored_key() {
+        #[derive(Debug, Clone)]
+        struct Name(&'static str, usize);
+        impl PartialEq for Name {
+            fn eq(&self, other: &Self) -> bool {
+                self.0 == other.0
+            }
+        }
+        impl Eq for Name {}
+        impl std::hash::Hash for Name {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.hash(state)
+            }
+        }
+        let root = ChainMap::new_with(map![Name("x", 3) => 0, Name("y", 5) => 1]);
+        let mut ch = root.extend_with(map![Name("x", 12) => 2]);
+        assert_eq!(ch.get_key_value(&Name("x", 0)).map(|(k, _)| k.1), Some(12));
+        assert_eq!(ch.get_key_value(&Name("y", 0)).map(|(k, _)| k.1), Some(5));
+        ch.hide(Name("y", 0));
+        assert!(ch.get_key_value(&Name("y", 0)).is_none());
+    }
+
+    #[test]
+    fn retain_write_protected() {
+        let mut ch = ChainMap::new_with(map![0 => 'a', 1 => 'b', 2 => 'c']);
+        ch.set_write_filter(|k| *k != 1);
+        ch.retain(|_, _| false);
+        assert_eq!(ch.collect(), map![1 => 'b']);
+        let mut ch = ChainMap::new_with(map![0 => 'a']).readonly();
+        ch.retain(|_, _| false);
+        assert_eq!(ch.get(&0), Some('a'));
+    }
+
+    #[test]
+    fn clear_local() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut scope = root.extend_with(map![0 => 'b', 1 => 'c']);
+        let ids = scope.layer_ids();
+        scope.hide(0);
+        scope.clear_local();
+        assert_eq!(scope.collect(), map![0 => 'a']);
+        assert_eq!(scope.layer_ids(), ids);
+        scope.insert(1, 'd');
+        assert_eq!(scope.get(&1), Some('d'));
+    }
+
+    #[test]
+    fn update_with() {
+        let root = ChainMap::new_with(map![0 => vec!['a']]);
+        let mut ch = root.extend_with(map![1 => vec![]]);
+        ch.update_with(&0, |v| v.push('b'));
+        ch.update_with(&1, |v| v.push('c'));
+        assert_eq!(root.get(&0), Some(vec!['a', 'b']));
+        assert_eq!(ch.get(&1), Some(vec!['c']));
+        let mut proto = root.extend().readonly().extend();
+        proto.set_write_up_promotion(true);
+        proto.update_with(&0, |v| v.clear());
+        assert_eq!(proto.get(&0), Some(vec![]));
+        assert_eq!(root.get(&0), Some(vec!['a', 'b']));
+    }
+
+    #[test]
+    #[should_panic(expected = "Key does not exist")]
+    fn update_with_hidden() {
+        let mut ch = ChainMap::new_with(map![0 => 'a']).extend();
+        ch.hide(0);
+        ch.update_with(&0, |v| *v = 'b');
+    }
+
+    #[test]
+    fn insert_all_iter() {
+        let mut ch = ChainMap::new_with(map![0 => 0]).extend_with(map![1 => 1]);
+        let overwritten = ch.insert_all((0..100).map(|i| (i, i * 2)));
+        assert_eq!(overwritten, vec![1]);
+        assert_eq!(ch.get(&50), Some(100));
+        ch.set_quota(Quota { max_keys: Some(101), max_bytes: None });
+        assert_eq!(ch.insert_all(vec![(100, 0), (100, 1), (0, 1)]), vec![100, 0]);
+    }
+
+    #[test]
+    fn extend_trait() {
+        fn fill<C: Extend<(u8, char)>>(c: &mut C) {
+            c.extend(vec![(0, 'a'), (1, 'b')]);
+        }
+        let root = ChainMap::new_with(map![2 => 'c']);
+        let mut ch = root.extend();
+        fill(&mut ch);
+        Extend::extend(&mut ch, Some((3, 'd')));
+        assert_eq!(ch.collect(), map![0 => 'a', 1 => 'b', 2 => 'c', 3 => 'd']);
+        assert_eq!(root.len(), 1);
+    }
+
+    #[test]
+    fn from_iter() {
+        let ch = (0..4).map(|i| (i, i * i)).collect::<ChainMap<_, _>>();
+        assert_eq!(ch.layer_ids().len(), 1);
+        assert_eq!(ch.get(&3), Some(9));
+        assert_eq!(ch.len(), 4);
+    }
+
+    #[test]
+    fn into_iter() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b', 2 => 'c']);
+        let mut ch = root.extend_with(map![0 => 'x']);
+        ch.hide(1);
+        let mut seen = Vec::new();
+        for (k, v) in &ch {
+            seen.push((k, v));
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, vec![(0, 'x'), (2, 'c')]);
+        let mut owned = root.into_iter().collect::<Vec<_>>();
+        owned.sort_unstable();
+        assert_eq!(owned, vec![(0, 'a'), (1, 'b'), (2, 'c')]);
+    }
+
+    #[test]
+    fn lazy_iter() {
+        let root = (0..1000).map(|i| (i, i)).collect::<ChainMap<_, _>>();
+        let mut ch = root.extend_with(map![0 => 10, 1 => 11]);
+        ch.hide(2);
+        let mut top = ch.iter().take(2).collect::<Vec<_>>();
+        top.sort_unstable();
+        assert_eq!(top, vec![(0, 10), (1, 11)]);
+        assert_eq!(ch.iter().count(), 999);
+        assert!(ch.iter().all(|(k, v)| ch.get(&k) == Some(v)));
+    }
+
+    #[test]
+    fn iter_layers() {
+        let root = ChainMap::new_with(map![0 => 'a']).locked();
+        let mut ch = root.extend_with(map![0 => 'b', 1 => 'c']);
+        let scope = ch.fork();
+        let layers = scope.iter_layers().collect::<Vec<_>>();
+        assert_eq!(layers.len(), 3);
+        assert_eq!(
+            layers.iter().map(|l| l.id).collect::<Vec<_>>(),
+            scope.layer_ids()
+        );
+        assert_eq!(layers[1].bindings, map![0 => 'b', 1 => 'c']);
+        assert_eq!(layers[2].depth, 2);
+        assert!(layers[2].locked && !layers[2].readonly && !layers[2].fallthrough);
+        ch.insert(2, 'd');
+        assert!(ch.iter_layers().next().unwrap().fallthrough);
+    }
+
+    #[test]
+    fn local_iter() {
+        let mut ch = ChainMap::new_with(map![0 => 'a']).extend_with(map![1 => 'b']);
+        let mut scope = ch.fork();
+        scope.insert(2, 'c');
+        ch.insert(3, 'd');
+        let mut local = ch.local_iter().collect::<Vec<_>>();
+        local.sort_unstable();
+        assert_eq!(local, vec![(1, 'b'), (3, 'd')]);
+        assert_eq!(scope.local_iter().collect::<Vec<_>>(), vec![(2, 'c')]);
+        assert_eq!(scope.iter().count(), 3);
+    }
+
+    #[test]
+    fn get_all() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch = root.extend().extend_with(map![0 => 'c']);
+        assert_eq!(ch.get_all(&0), vec!['c', 'a']);
+        assert_eq!(ch.get_all(&1), vec!['b']);
+        assert_eq!(ch.get_all(&2), vec![]);
+        ch.hide(1);
+        assert_eq!(ch.get_all(&1), vec![]);
+    }
+
+    #[test]
+    fn drain() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut scratch = root.extend_with(map![0 => 'b', 1 => 'c']);
+        let mut parent = root.extend();
+        parent.insert_all(scratch.drain());
+        assert_eq!(parent.collect(), map![0 => 'b', 1 => 'c']);
+        assert_eq!(scratch.collect(), map![0 => 'a']);
+        assert_eq!(scratch.drain().count(), 0);
+    }
+
+    #[test]
+    fn swap() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch = root.extend_with(map![2 => 'c', 3 => 'd']);
+        ch.swap(&2, &3);
+        assert_eq!((ch.get(&2), ch.get(&3)), (Some('d'), Some('c')));
+        ch.swap(&0, &2);
+        assert_eq!((root.get(&0), ch.get(&2)), (Some('d'), Some('a')));
+        ch.swap(&1, &1);
+        assert_eq!(ch.get(&1), Some('b'));
+    }
+
+    #[test]
+    #[should_panic(expected = "Key does not exist, failed to swap")]
+    fn swap_missing() {
+        let mut ch = ChainMap::new_with(map![0 => 'a']);
+        ch.swap(&0, &1);
+    }
+
+    #[test]
+    fn borrowed_lookups() {
+        let root = ChainMap::new_with(map![String::from("a") => 0, String::from("b") => 1]);
+        let ch = root.extend_cache().extend_with(map![String::from("a") => 2]);
+        assert_eq!(ch.get("a"), Some(2));
+        assert_eq!(ch.get("b"), Some(1));
+        assert_eq!(ch.local_get("b"), None);
+        assert!(ch.contains_key("b") && !ch.contains_key("c"));
+        assert_eq!(ch.get_key_value("a"), Some((String::from("a"), 2)));
+        assert_eq!(ch.get_all("a"), vec![2, 0]);
+        assert_eq!(ch.get_within("b", 0), Err(DepthExceeded { max_depth: 0 }));
+        ch.get("b");
+        assert_eq!(ch.tail().cache_stats(), Some(CacheStats { hits: 1, misses: 1 }));
+        ch.tail().invalidate("b");
+        assert_eq!(ch.classify("a"), Binding::Local);
+        assert_eq!(ch.occurrences("a"), 2);
+        assert!(ch.flags_for("b").is_some());
+        assert_eq!(ch.get_traced("b").0, Some(1));
+        assert_eq!(ch.local_get_traced("b").0, None);
+        let mut ch = root.extend_log();
+        ch.insert(String::from("c"), 3);
+        ch.insert(String::from("c"), 4);
+        assert_eq!(ch.history("c"), vec![3, 4]);
+        assert_eq!(ch.remove("c"), Some(4));
+        ch.insert(String::from("c"), 5);
+        assert_eq!(ch.pop("c"), Some(5));
+        ch.hide(String::from("a"));
+        assert!(ch.unhide("a"));
+        assert_eq!(ch.get("a"), Some(0));
+        ch.insert(String::from("c"), 6);
+        ch.update("c", 7);
+        ch.update_with("c", |v| *v += 1);
+        assert_eq!(ch.try_update("d", 0), Err(ChainMapError::KeyMissing));
+        ch.update_or("d", 9);
+        assert_eq!(ch.update_or_report("a", 10), UpdateOutcome::Updated);
+        *ch.get_mut("d").unwrap() += 1;
+        assert_eq!(ch.compare_and_swap("d", &10, 11), Ok(()));
+        ch.swap("c", "d");
+        assert_eq!(ch.history("c"), vec![3, 6, 7, 8, 11]);
+        assert_eq!((ch.get("c"), ch.get("d")), (Some(11), Some(8)));
+        assert_eq!(ch.rename_key("d", String::from("e"), false), 1);
+        ch.set_write_filter(|k: &String| k != "b");
+        assert!(ch.is_effectively_writable("a"));
+        assert!(!ch.is_effectively_writable("b"));
+        assert_eq!(ch.update_trace("b").resolved_at(), None);
+        assert_eq!(ch.get_routed("e"), Some(8));
+        assert_eq!(root.get("a"), Some(10));
+    }
+
+    #[test]
+    fn borrowed_lookups_elsewhere() {
+        let root = ChainMap::new_with(map![String::from("a") => 0]);
+        let view = root.derive_view(|k: &String, v: &i32| Some((k.clone(), v + 1)));
+        assert_eq!(view.get("a"), Some(1));
+        assert!(!view.contains_key("b"));
+        let frozen = root.extend_with(map![String::from("b") => 1]).finish();
+        assert_eq!(frozen.get("b"), Some(&1));
+        assert!(frozen.contains_key("a"));
+        let mut local = LocalChainMap::new_with(map![String::from("a") => 0]).extend();
+        local.update("a", 2);
+        assert_eq!(local.get("a"), Some(2));
+        assert_eq!(local.local_get("a"), None);
+        assert!(local.contains_key("a"));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn borrowed_lookups_sync() {
+        use std::time::Duration;
+        let root = SyncChainMap::new_with(map![String::from("a") => 0]);
+        let mut ch = root.extend();
+        ch.update("a", 1);
+        assert_eq!(ch.get("a"), Some(1));
+        assert_eq!(ch.local_get("a"), None);
+        assert!(ch.contains_key("a"));
+        assert_eq!(ch.wait_for("a", Duration::ZERO), Some(1));
+        let mirror = ch.mirror();
+        assert_eq!(mirror.get("a"), Some(1));
+        assert_eq!(mirror.local_get("a"), None);
+        assert!(mirror.contains_key("a"));
+        assert_eq!(mirror.wait_for("b", Duration::ZERO), None);
+        let leaves = root.thread_local_leaf();
+        leaves.insert(String::from("b"), 2);
+        assert_eq!(leaves.get("b"), Some(2));
+    }
+
+    #[test]
+    fn try_update() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch = root.extend().readonly().extend_with(map![2 => 'c']).locked().extend();
+        assert_eq!(ch.try_update(&0, 'x'), Err(ChainMapError::ReadOnlyBarrier));
+        assert_eq!(ch.try_update(&2, 'x'), Err(ChainMapError::KeyLocked));
+        assert_eq!(ch.try_update(&3, 'x'), Err(ChainMapError::KeyMissing));
+        ch.insert(3, 'd');
+        assert_eq!(ch.try_update(&3, 'x'), Ok(()));
+        ch.lock();
+        assert_eq!(ch.try_insert(4, 'e'), Err(ChainMapError::Locked));
+        assert_eq!(ChainMapError::KeyLocked.to_string(), "Key is locked");
+    }
+
+    #[test]
+    fn poison_recovery() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        let mut ch = ChainMap::new_with(map![0 => 'a']).extend_with(map![1 => 'b']);
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            ch.update_with(&1, |_| panic!("in update_with"));
+        }));
+        assert!(res.is_err());
+        assert_eq!(ch.validate(), vec![Issue::Poisoned { depth: 0 }]);
+        assert_eq!(ch.get(&1), Some('b'));
+        ch.insert(2, 'c');
+        ch.update(&1, 'x');
+        assert_eq!(ch.collect(), map![0 => 'a', 1 => 'x', 2 => 'c']);
+        assert_eq!(ch.len(), 3);
+        assert_eq!(ch.iter().count(), 3);
+        assert_eq!(ch.iter_resumable().count(), 3);
+        assert_eq!(ch.local_overrides().count(), 2);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let root = ChainMap::new_with(map![0 => 'a']);
+        let mut ch = root.extend_with(map![1 => 'b']);
+        assert_eq!(ch.compare_and_swap(&0, &'x', 'c'), Err('c'));
+        assert_eq!(ch.compare_and_swap(&0, &'a', 'c'), Ok(()));
+        assert_eq!(ch.compare_and_swap(&1, &'b', 'd'), Ok(()));
+        assert_eq!(root.get(&0), Some('c'));
+        assert_eq!(ch.local_get(&1), Some('d'));
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn sync_chainmap() {
+        use std::thread;
+        let root = SyncChainMap::new_with(map![0 => 'a']).locked();
+        let mut ch = root.extend_with(map![1 => 'b']);
+        let workers = (0..4)
+            .map(|i| {
+                let mut local = ch.extend();
+                let mut shared = ch.clone_linked();
+                thread::spawn(move || {
+                    local.insert(2, 'c');
+                    shared.update(&1, 'x');
+                    assert!(local.is_unlocked() && shared.contains_key(&0));
+                    (i, local.collect())
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            let (_, collected) = worker.join().unwrap();
+            assert_eq!(collected[&0], 'a');
+            assert_eq!(collected[&2], 'c');
+        }
+        assert_eq!(ch.collect(), map![0 => 'a', 1 => 'x']);
+        let branch = ch.fork_with(map![3 => 'd']);
+        ch.insert(4, 'e');
+        assert_eq!(branch.get(&4), None);
+        assert_eq!(ch.local_get(&3), None);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn thread_local_leaf() {
+        use std::sync::Arc;
+        use std::thread;
+        let mut root = SyncChainMap::new_with(map![0 => 'a']);
+        let leaves = Arc::new(root.thread_local_leaf());
+        let workers = (1..=4)
+            .map(|i| {
+                let leaves = Arc::clone(&leaves);
+                thread::spawn(move || {
+                    assert_eq!(leaves.get(&i), None);
+                    leaves.insert(i, 'b');
+                    leaves.insert(0, 'c');
+                    let seen = (1..=4).filter(|k| leaves.get(k).is_some()).count();
+                    if i == 1 {
+                        assert!(leaves.clear());
+                        assert!(!leaves.clear());
+                    }
+                    (seen, leaves.get(&i))
+                })
+            })
+            .collect::<Vec<_>>();
+        for (i, worker) in workers.into_iter().enumerate() {
+            let own = if i == 0 { None } else { Some('b') };
+            assert_eq!(worker.join().unwrap(), (1, own));
+        }
+        // Leaves are dropped along with their thread
+        assert_eq!(leaves.threads(), 0);
+        leaves.insert(5, 'd');
+        root.insert(6, 'e');
+        assert_eq!(leaves.leaf().collect(), map![0 => 'a', 5 => 'd', 6 => 'e']);
+        assert_eq!(leaves.parent().get(&5), None);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn mirror() {
+        use std::thread;
+        use std::time::Duration;
+        let mut root = SyncChainMap::new_with(map![0 => 'a']);
+        let mut ch = root.extend();
+        let mirror = ch.mirror();
+        let mut sub = mirror.subscribe();
+        assert!(!sub.poll());
+        let monitor = thread::spawn(move || {
+            assert!(sub.wait(Duration::from_secs(10)));
+            assert!(!sub.wait(Duration::from_millis(1)));
+            mirror.get(&1)
+        });
+        ch.insert(1, 'b');
+        assert_eq!(monitor.join().unwrap(), Some('b'));
+        let mirror = ch.mirror();
+        let mut sub = mirror.subscribe();
+        root.update(&0, 'c');
+        assert!(sub.poll() && !sub.poll());
+        assert_eq!(mirror.collect(), map![0 => 'c', 1 => 'b']);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn wait_for() {
+        use std::thread;
+        use std::time::Duration;
+        let root = SyncChainMap::new_with(map![0 => 'a']);
+        let mut config = root.extend();
+        let reader = config.clone_linked();
+        let consumer = thread::spawn(move || reader.wait_for(&1, Duration::from_secs(10)));
+        config.insert(2, 'b');
+        config.insert(1, 'c');
+        assert_eq!(consumer.join().unwrap(), Some('c'));
+        assert_eq!(root.wait_for(&0, Duration::from_secs(0)), Some('a'));
+        assert_eq!(root.mirror().wait_for(&1, Duration::from_millis(1)), None);
+    }
+
+    // Run with `RUSTFLAGS="--cfg loom" cargo test --features sync --lib -- --exact test::loom_sync_layers`:
+    // under `loom` the other tests create locks outside of a model and must be filtered out.
+    #[test]
+    #[cfg(all(loom, feature = "sync"))]
+    fn loom_sync_layers() {
+        loom::model(|| {
+            let root = SyncChainMap::new_with(map![0 => 'a']);
+            let mut left = root.extend();
+            let mut right = root.extend_with(map![1 => 'b']);
+            let reader = right.clone_linked();
+            let writer = loom::thread::spawn(move || {
+                left.update(&0, 'x');
+                left.insert(2, 'c');
+                left
+            });
+            let seen = reader.get(&0);
+            right.update(&1, 'y');
+            let left = writer.join().unwrap();
+            assert!(seen == Some('a') || seen == Some('x'));
+            assert_eq!(root.get(&0), Some('x'));
+            assert_eq!(right.collect(), map![0 => 'x', 1 => 'y']);
+            assert_eq!(left.get(&2), Some('c'));
+        });
+    }
+
+    #[test]
+    fn local_chainmap() {
+        let root = LocalChainMap::new_with(map![0 => 'a']).locked();
+        let mut ch = root.extend_with(map![1 => 'b']);
+        let mut linked = ch.clone_linked();
+        linked.update(&1, 'x');
+        assert_eq!(ch.insert(2, 'c'), None);
+        let mut copy = ch.clone();
+        copy.insert(3, 'd');
+        assert_eq!(ch.collect(), map![0 => 'a', 1 => 'x', 2 => 'c']);
+        assert!(!ch.contains_key(&3) && copy.contains_key(&3));
+        let branch = ch.fork();
+        ch.insert(4, 'e');
+        assert_eq!(branch.get(&4), None);
+        assert!(root.is_locked());
+    }
+
+    #[test]
+    #[cfg(feature = "arc-swap")]
+    fn read_mostly() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']).read_mostly();
+        let mut ch = root.extend_with(map![2 => 'c']);
+        assert!(ch.is_read_mostly());
+        ch.insert(3, 'd');
+        ch.update(&0, 'x');
+        assert_eq!(ch.get(&3), Some('d'));
+        assert_eq!(ch.get(&0), Some('x'));
+        // The layer is held locked, but lookups only read its published copy
+        let layer = root.link().elem.lock_recover();
+        assert_eq!(ch.get(&1), Some('b'));
+        drop(layer);
+        assert!(!ChainMap::<i32, char>::new().is_read_mostly());
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn par_collect() {
+        let root = ChainMap::new_with((0..1000).map(|i| (i, 0)).collect());
+        let mut ch = root.extend_with((500..1500).map(|i| (i, 1)).collect());
+        ch.hide(0);
+        let ch = ch.extend_with(map![1 => 2, 2000 => 2]);
+        assert_eq!(ch.par_collect(), ch.collect());
+        assert_eq!(ch.par_collect().len(), 1500);
+    }
+
+    #[test]
+    fn get_many() {
+        let root = ChainMap::new_with(map![String::from("a") => 0, String::from("b") => 1]);
+        let mut ch = root.extend_with(map![String::from("c") => 2, String::from("a") => 3]);
+        ch.hide(String::from("b"));
+        assert_eq!(
+            ch.get_many(&["a", "b", "c", "d", "a"]),
+            vec![Some(3), None, Some(2), None, Some(3)]
+        );
+        assert_eq!(root.get_many(&["b"]), vec![Some(1)]);
+        assert!(root.get_many::<str>(&[]).is_empty());
+    }
+
+    #[test]
+    fn bloom_filtered() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']).bloom_filtered();
+        let mid = root.extend_with(map![2 => 'c']);
+        let mut ch = mid.extend();
+        assert!(ch.is_bloom_filtered());
+        ch.insert(3, 'd');
+        ch.update(&0, 'x');
+        assert_eq!(ch.get(&3), Some('d'));
+        assert_eq!(ch.get(&0), Some('x'));
+        ch.batch(|layer| layer.insert(4, 'e'));
+        assert_eq!(ch.get(&4), Some('e'));
+        assert_eq!(ch.remove(&3), Some('d'));
+        assert_eq!(ch.get(&3), None);
+        // The middle layer is held locked, but its filter rules out the key
+        let layer = mid.link().elem.lock_recover();
+        assert_eq!(ch.get(&1), Some('b'));
+        drop(layer);
+    }
+
+    #[test]
+    fn memoization() {
+        let root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut mid = root.extend();
+        let mut ch = mid.extend().extend();
+        ch.set_memoization();
+        assert_eq!(ch.get(&0), Some('a'));
+        assert_eq!(ch.get(&0), Some('a'));
+        mid.insert(0, 'c');
+        assert_eq!(ch.get(&0), Some('c'));
+        ch.update(&0, 'd');
+        assert_eq!(ch.get(&0), Some('d'));
+        assert_eq!(ch.get(&1), Some('b'));
+        ch.hide(1);
+        assert_eq!(ch.get(&1), None);
+        ch.clear_memoization();
+        assert_eq!(ch.get(&0), Some('d'));
+    }
+
+    #[test]
+    fn flatten() {
+        let mut root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let mut ch = root.extend_with(map![2 => 'c']).extend_with(map![0 => 'd']);
+        ch.hide(1);
+        ch.lock();
+        ch.flatten();
+        assert_eq!(ch.layer_ids().len(), 1);
+        assert_eq!(ch.collect(), map![0 => 'd', 2 => 'c']);
+        assert!(ch.is_locked());
+        root.insert(3, 'e');
+        assert_eq!(ch.get(&3), None);
+    }
+
+    #[test]
+    fn squash_into_parent() {
+        let mut root = ChainMap::new_with(map![0 => 'a', 1 => 'b']);
+        let scope = root.fork();
+        let mut ch = scope.extend_with(map![0 => 'c', 2 => 'd']);
+        ch.hide(1);
+        assert_eq!(ch.squash_into_parent(), Ok(()));
+        assert_eq!(ch.layer_ids(), scope.layer_ids());
+        assert_eq!(scope.collect(), map![0 => 'c', 2 => 'd']);
+        assert_eq!(root.collect(), map![0 => 'a', 1 => 'b']);
+        assert_eq!(ch.clone_linked().squash_into_parent(), Err(ChainMapError::Shared));
+        let mut locked = root.locked().extend();
+        assert_eq!(locked.squash_into_parent(), Err(ChainMapError::Locked));
+        assert_eq!(ChainMap::<i32, char>::new().squash_into_parent(), Err(ChainMapError::NoParent));
+    }
+
+    #[test]
+    fn get_shared() {
+        let mut root = SharedChainMap::new();
+        root.insert_shared(0, vec!['a'; 1000]);
+        let mut ch = root.extend();
+        let first = ch.get_shared(&0).unwrap();
+        assert!(Arc::ptr_eq(&first, &ch.get_shared(&0).unwrap()));
+        ch.update_with(&0, |v| Arc::make_mut(v).push('b'));
+        assert_eq!(first.len(), 1000);
+        assert_eq!(root.get_shared(&0).unwrap().len(), 1001);
+        let names: SharedChainMap<i32, str> = ChainMap::new_with(map![0 => Arc::from("a")]);
+        assert_eq!(names.get_shared(&0).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn get_ref() {
+        struct Handle(&'static str);
+        let mut root = ChainMap::new_with(map![0 => Handle("a")]);
+        root.insert(1, Handle("b"));
+        let mut ch = root.extend_with(map![1 => Handle("c")]);
+        ch.hide(0);
+        assert_eq!(ch.get_ref(&1).unwrap().0, "c");
+        assert!(ch.get_ref(&0).is_none());
+        assert_eq!(root.get_ref(&0).map(|h| h.0), Some("a"));
+        ch.get_mut(&1).unwrap().0 = "d";
+        ch.update(&1, Handle("e"));
+        assert_eq!(ch.get_ref(&1).unwrap().0, "e");
+        assert!(ch.contains_key(&1) && !ch.contains_key(&0));
+        assert_eq!(ch.remove(&1).map(|h| h.0), Some("e"));
+    }
+
+    #[test]
+    fn non_clone_values() {
+        #[derive(Debug, PartialEq)]
+        struct Handle(&'static str);
+        let mut root: ChainMap<i32, Handle> = ChainMap::default();
+        Extend::extend(&mut root, vec![(0, Handle("a"))]);
+        let root = root.bloom_filtered();
+        assert!(root.is_bloom_filtered());
+        let mut ch: ChainMap<_, _> = vec![(1, Handle("b"))].into_iter().collect();
+        ch.as_flat_mut().insert(2, Handle("c"));
+        let options = BulkOptions { presize: true, dedup: true };
+        let stats = ch.bulk_load(vec![(3, Handle("d"))], options);
+        assert_eq!(stats.inserted, 1);
+        assert_eq!(ch.get_ref(&2).map(|h| h.0), Some("c"));
+        assert_eq!(root.get_ref(&0).map(|h| h.0), Some("a"));
+    }
+
+    #[test]
+    fn non_clone_keys() {
+        #[derive(PartialEq, Eq, Hash)]
+        struct Name(Box<str>);
+        let name = |s: &str| Name(s.into());
+        let mut root = ChainMap::new_with(map![name("a") => 1]);
+        root.insert(name("b"), 2);
+        let mut ch = root.extend();
+        ch.insert(name("a"), 3);
+        assert_eq!(ch.get(&name("a")), Some(3));
+        assert_eq!(ch.get(&name("b")), Some(2));
+        assert!(ch.contains_key(&name("b")) && !ch.contains_key(&name("c")));
+        assert_eq!(ch.remove(&name("a")), Some(3));
+        assert_eq!(ch.get(&name("a")), Some(1));
+        assert_eq!(root.try_insert(name("c"), 4), Ok(None));
+        assert_eq!(ch.get(&name("c")), Some(4));
+    }
+
+    #[test]
+    fn non_clone_keys_traits() {
+        #[derive(PartialEq, Eq, Hash)]
+        struct Name(Box<str>);
+        let name = |s: &str| Name(s.into());
+        let mut root: ChainMap<Name, i32> = ChainMap::default();
+        Extend::extend(&mut root, vec![(name("a"), 1)]);
+        assert_eq!(root.insert_all(vec![(name("a"), 2), (name("b"), 3)]).len(), 1);
+        let root = root.bloom_filtered();
+        let mut ch: ChainMap<_, _> = vec![(name("c"), 4)].into_iter().collect();
+        ch.as_flat_mut().insert(name("d"), 5);
+        let options = BulkOptions { presize: false, dedup: false };
+        let stats = ch.bulk_load(vec![(name("d"), 6), (name("e"), 7)], options);
+        assert_eq!((stats.inserted, stats.overwritten), (1, 1));
+        assert_eq!(root.get(&name("a")), Some(2));
+        assert_eq!(ch.get(&name("d")), Some(6));
+    }
 }
@@ -0,0 +1,264 @@
+//! A chain for handles that never leave their thread
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// Same as a `ChainMap`, but each layer is a `RefCell` instead of a `Mutex`
+///
+/// Meant for interpreter hot paths: borrowing a layer costs a counter update rather than a lock.
+///
+/// Only the core of the `ChainMap` API is provided, with the same behavior: `new`, `new_with`,
+/// `insert`, `update`, `get`, `local_get`, `contains_key`, `extend`, `extend_with`, `fork`,
+/// `fork_with`, `lock`, `unlock`, `locked`, `unlocked`, `is_locked`, `is_unlocked`, `readonly`,
+/// `clone_linked` and `collect`, along with `Clone` and `Default`. Tombstones, quotas,
+/// write-up promotion, iterators, snapshots, caches and the other extensions are not available.
+pub struct LocalChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    head: Rc<LocalNode<K, V>>,
+}
+
+type LocalLink<K, V> = Option<Rc<LocalNode<K, V>>>;
+
+struct LocalNode<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    elem: RefCell<HashMap<K, V>>,
+    next: LocalLink<K, V>,
+    fallthrough: bool,
+    unlocked: Cell<bool>,
+    write_auth: Cell<bool>,
+}
+
+impl<K, V> LocalNode<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn new(elem: HashMap<K, V>, next: LocalLink<K, V>, fallthrough: bool) -> Self {
+        Self {
+            elem: RefCell::new(elem),
+            next,
+            fallthrough,
+            unlocked: Cell::new(true),
+            write_auth: Cell::new(true),
+        }
+    }
+}
+
+impl<K, V> LocalChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Create a new empty root
+    pub fn new() -> Self {
+        Self::new_with(HashMap::new())
+    }
+
+    /// Create a new root and initialize with given map
+    pub fn new_with(h: HashMap<K, V>) -> Self {
+        Self {
+            head: Rc::new(LocalNode::new(h, None, false)),
+        }
+    }
+
+    /// Create a new binding in the toplevel, see `ChainMap::insert`
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        if self.is_locked() {
+            panic!("Map is locked, could not insert");
+        }
+        self.head.elem.borrow_mut().insert(key, val)
+    }
+
+    /// Replace old value with new, see `ChainMap::update`
+    /// # Panics
+    /// - if `key` does not already exist
+    /// - if first layer with `key` is locked
+    /// - if `key` is only found after a write-protected layer
+    pub fn update<Q>(&mut self, key: &Q, newval: V)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if !m.write_auth.get() {
+                break;
+            }
+            if let Some(val) = m.elem.borrow_mut().get_mut(key) {
+                if m.unlocked.get() {
+                    *val = newval;
+                    return;
+                } else {
+                    panic!("Key is locked, failed to update");
+                }
+            }
+            r = m.next.as_ref();
+        }
+        panic!("Key does not exist, failed to update");
+    }
+
+    /// Retrieve value associated with the first appearance of `key` in the chain
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(val) = m.elem.borrow().get(key) {
+                return Some(val.clone());
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Check associated value only in topmost layers: stops at the first non-fallthrough level
+    pub fn local_get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(val) = m.elem.borrow().get(key) {
+                return Some(val.clone());
+            }
+            if !m.fallthrough {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if m.elem.borrow().contains_key(key) {
+                return true;
+            }
+            r = m.next.as_ref();
+        }
+        false
+    }
+
+    pub fn extend(&self) -> Self {
+        self.extend_with(HashMap::new())
+    }
+
+    /// Create a new scope, see `ChainMap::extend_with`
+    pub fn extend_with(&self, h: HashMap<K, V>) -> Self {
+        Self {
+            head: Rc::new(LocalNode::new(h, Some(Rc::clone(&self.head)), false)),
+        }
+    }
+
+    pub fn fork(&mut self) -> Self {
+        self.fork_with(HashMap::new())
+    }
+
+    /// Create a new branch, see `ChainMap::fork_with`
+    pub fn fork_with(&mut self, h: HashMap<K, V>) -> Self {
+        let newlevel = self.extend_with(h);
+        self.head = Rc::new(LocalNode::new(HashMap::new(), Some(Rc::clone(&self.head)), true));
+        newlevel
+    }
+
+    pub fn lock(&mut self) {
+        self.head.unlocked.set(false);
+    }
+
+    pub fn unlock(&mut self) {
+        self.head.unlocked.set(true);
+    }
+
+    pub fn locked(mut self) -> Self {
+        self.lock();
+        self
+    }
+
+    pub fn unlocked(mut self) -> Self {
+        self.unlock();
+        self
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.head.unlocked.get()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        !self.is_unlocked()
+    }
+
+    /// Resulting layer cannot modify any value lower in the map
+    pub fn readonly(self) -> Self {
+        self.head.write_auth.set(false);
+        self
+    }
+
+    /// Another handle on the same toplevel, see `ChainMap::clone_linked`
+    pub fn clone_linked(&self) -> Self {
+        Self {
+            head: Rc::clone(&self.head),
+        }
+    }
+
+    /// Gather all bindings in a single `HashMap`, see `ChainMap::collect`
+    pub fn collect(&self) -> HashMap<K, V> {
+        let mut h = HashMap::new();
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            for (key, val) in m.elem.borrow().iter() {
+                h.entry(key.clone()).or_insert_with(|| val.clone());
+            }
+            r = m.next.as_ref();
+        }
+        h
+    }
+}
+
+impl<K, V> Clone for LocalChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Copy the toplevel, see `ChainMap::clone`
+    fn clone(&self) -> Self {
+        let node = &self.head;
+        let copy = LocalNode::new(
+            node.elem.borrow().clone(),
+            node.next.clone(),
+            node.fallthrough,
+        );
+        copy.unlocked.set(node.unlocked.get());
+        copy.write_auth.set(node.write_auth.get());
+        Self {
+            head: Rc::new(copy),
+        }
+    }
+}
+
+impl<K, V> Default for LocalChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,114 @@
+//! Memoized resolution of keys, relative to a handle
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::{ChainMap, Mutex, Node, Ordering, Recover, GENERATION};
+
+/// Layer where a key was last resolved
+struct Resolution<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Generations of the layers down to `layer` included, valid as long as they all match
+    generations: Vec<usize>,
+    /// Value of `GENERATION` when the resolution was last known to be valid
+    stamp: usize,
+    layer: Rc<Node<K, V>>,
+}
+
+pub(crate) struct Memo<K, V>
+where
+    K: Eq + Hash,
+{
+    resolutions: Mutex<HashMap<K, Resolution<K, V>>>,
+    /// Set by `set_memoization`, so that keys can be copied without requiring `K: Clone`
+    copy_key: fn(&K) -> K,
+}
+
+impl<K, V> Resolution<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Whether no layer down to the one that bound the key was modified since
+    fn is_valid(&self, top: &Rc<Node<K, V>>) -> bool {
+        let mut r = Some(top);
+        for &generation in &self.generations {
+            match r {
+                Some(m) if m.generation.load(Ordering::Relaxed) == generation => {
+                    r = m.next.as_ref();
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash,
+    V: Clone,
+{
+    /// Let `get` remember the layer where each key is found
+    ///
+    /// Repeated lookups of a key then go straight to that layer for as long as no layer above
+    /// it is modified, which only takes a single comparison if no layer of any chain was.
+    /// Read caches are not used by memoized lookups. The memo is relative to this handle,
+    /// and is not inherited by `extend`, `fork` or `clone`.
+    pub fn set_memoization(&mut self)
+    where
+        K: Clone,
+    {
+        self.memo = Some(Memo {
+            resolutions: Mutex::new(HashMap::new()),
+            copy_key: K::clone,
+        });
+    }
+
+    pub fn clear_memoization(&mut self) {
+        self.memo = None;
+    }
+
+    /// Lookup of `get` through the memo
+    pub(crate) fn get_memoized<Q>(&self, memo: &Memo<K, V>, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let top = self.link();
+        let stamp = GENERATION.load(Ordering::Relaxed);
+        let mut resolutions = memo.resolutions.lock_recover();
+        if let Some(resolution) = resolutions.get_mut(key) {
+            if resolution.stamp == stamp || resolution.is_valid(top) {
+                resolution.stamp = stamp;
+                if let Some(val) = resolution.layer.lookup(key) {
+                    return Some(val);
+                }
+            }
+        }
+        let mut generations = Vec::new();
+        let mut r = Some(top);
+        while let Some(m) = r {
+            // Read before the lookup: a modification made in between invalidates the resolution
+            generations.push(m.generation.load(Ordering::Relaxed));
+            if let Some(val) = m.lookup(key) {
+                let resolution = Resolution {
+                    generations,
+                    stamp,
+                    layer: Rc::clone(m),
+                };
+                m.with_stored_key(key, |key| {
+                    resolutions.insert((memo.copy_key)(key), resolution);
+                });
+                return Some(val);
+            }
+            if m.hides(key) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+}
@@ -0,0 +1,126 @@
+//! Read-only handles for monitoring threads
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::sync::Changes;
+use crate::SyncChainMap;
+
+/// A handle on a `SyncChainMap` that can only read, see `SyncChainMap::mirror`
+///
+/// Meant to be handed to metrics or debugging threads: it grants no way to modify
+/// the chain or to create new layers on it.
+pub struct Mirror<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    chain: SyncChainMap<K, V>,
+}
+
+/// Notifications of the modifications of a chain, obtained from `Mirror::subscribe`
+///
+/// Modifications made through any handle created from the same root are reported,
+/// including those of branches that the mirror does not see.
+pub struct Subscription {
+    changes: Arc<Changes>,
+    seen: usize,
+}
+
+impl<K, V> Mirror<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chain.get(key)
+    }
+
+    pub fn local_get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chain.local_get(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chain.contains_key(key)
+    }
+
+    /// Block until `key` is bound in the chain or `timeout` elapses, see `SyncChainMap::wait_for`
+    pub fn wait_for<Q>(&self, key: &Q, timeout: Duration) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.chain.wait_for(key, timeout)
+    }
+
+    pub fn collect(&self) -> HashMap<K, V> {
+        self.chain.collect()
+    }
+
+    /// Start watching for modifications, made from now on
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            changes: Arc::clone(&self.chain.changes),
+            seen: self.chain.changes.version(),
+        }
+    }
+}
+
+impl<K, V> Clone for Mirror<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            chain: self.chain.clone_linked(),
+        }
+    }
+}
+
+impl Subscription {
+    /// Whether the chain was modified since the last call to `poll` or `wait`
+    pub fn poll(&mut self) -> bool {
+        let version = self.changes.version();
+        let changed = version != self.seen;
+        self.seen = version;
+        changed
+    }
+
+    /// Block until the chain is modified or `timeout` elapses, return whether it was modified
+    ///
+    /// Returns immediately if a modification happened since the last call to `poll` or `wait`.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        let version = self.changes.wait(self.seen, timeout);
+        let changed = version != self.seen;
+        self.seen = version;
+        changed
+    }
+}
+
+impl<K, V> SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Read-only handle on the same toplevel, that can be sent to another thread
+    pub fn mirror(&self) -> Mirror<K, V> {
+        Mirror {
+            chain: self.clone_linked(),
+        }
+    }
+}
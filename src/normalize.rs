@@ -0,0 +1,159 @@
+//! Normalization of `String` keys at the boundary of a chain
+
+use std::sync::Arc;
+
+#[cfg(feature = "unicode-normalization")]
+use unicode_normalization::UnicodeNormalization;
+
+use crate::ChainMap;
+
+/// A pipeline of transformations applied to keys before they reach the chain
+///
+/// Steps are always applied in the same order: trimming, then lowercasing,
+/// then Unicode normalization.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyNormalizer {
+    trim: bool,
+    lowercase: bool,
+    #[cfg(feature = "unicode-normalization")]
+    nfc: bool,
+}
+
+impl KeyNormalizer {
+    /// Pipeline that leaves keys untouched
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove leading and trailing whitespace
+    pub fn trim(mut self) -> Self {
+        self.trim = true;
+        self
+    }
+
+    /// Convert to lowercase
+    pub fn lowercase(mut self) -> Self {
+        self.lowercase = true;
+        self
+    }
+
+    /// Convert to Unicode Normalization Form C
+    #[cfg(feature = "unicode-normalization")]
+    pub fn nfc(mut self) -> Self {
+        self.nfc = true;
+        self
+    }
+
+    /// Apply the pipeline to `key`
+    pub fn normalize(&self, key: &str) -> String {
+        let key = if self.trim { key.trim() } else { key };
+        let key = if self.lowercase {
+            key.to_lowercase()
+        } else {
+            key.to_string()
+        };
+        #[cfg(feature = "unicode-normalization")]
+        let key = if self.nfc { key.nfc().collect() } else { key };
+        key
+    }
+}
+
+/// A `ChainMap` with `String` keys, whose keys are all normalized
+///
+/// Every key given to or stored by this wrapper goes through the same `KeyNormalizer`,
+/// so that `"Timeout"`, `"timeout "` and `"TIMEOUT"` resolve to the same binding.
+/// Handles created with `extend` and `fork` share the normalizer.
+pub struct NormalizedChainMap<V>
+where
+    V: Clone,
+{
+    chain: ChainMap<String, V>,
+    normalizer: Arc<KeyNormalizer>,
+}
+
+impl<V> NormalizedChainMap<V>
+where
+    V: Clone,
+{
+    /// Create a new empty root
+    pub fn new(normalizer: KeyNormalizer) -> Self {
+        Self {
+            chain: ChainMap::new(),
+            normalizer: Arc::new(normalizer),
+        }
+    }
+
+    /// Wrap an existing chain
+    ///
+    /// Keys already bound in `chain` are not normalized retroactively.
+    pub fn from_chain(chain: ChainMap<String, V>, normalizer: KeyNormalizer) -> Self {
+        Self {
+            chain,
+            normalizer: Arc::new(normalizer),
+        }
+    }
+
+    /// Apply the normalizer of this chain to `key`
+    pub fn normalize(&self, key: &str) -> String {
+        self.normalizer.normalize(key)
+    }
+
+    /// Underlying chain, with keys in normalized form
+    pub fn as_chain(&self) -> &ChainMap<String, V> {
+        &self.chain
+    }
+
+    /// Unwrap the underlying chain
+    pub fn into_chain(self) -> ChainMap<String, V> {
+        self.chain
+    }
+
+    /// See `ChainMap::insert`
+    pub fn insert(&mut self, key: &str, val: V) -> Option<V> {
+        let key = self.normalize(key);
+        self.chain.insert(key, val)
+    }
+
+    /// See `ChainMap::get`
+    pub fn get(&self, key: &str) -> Option<V> {
+        self.chain.get(&self.normalize(key))
+    }
+
+    /// See `ChainMap::local_get`
+    pub fn local_get(&self, key: &str) -> Option<V> {
+        self.chain.local_get(&self.normalize(key))
+    }
+
+    /// See `ChainMap::contains_key`
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.chain.contains_key(&self.normalize(key))
+    }
+
+    /// See `ChainMap::update`
+    pub fn update(&mut self, key: &str, newval: V) {
+        let key = self.normalize(key);
+        self.chain.update(&key, newval);
+    }
+
+    /// See `ChainMap::update_or`
+    pub fn update_or(&mut self, key: &str, newval: V) {
+        let key = self.normalize(key);
+        self.chain.update_or(&key, newval);
+    }
+
+    /// See `ChainMap::extend`
+    pub fn extend(&self) -> Self {
+        Self {
+            chain: self.chain.extend(),
+            normalizer: Arc::clone(&self.normalizer),
+        }
+    }
+
+    /// See `ChainMap::fork`
+    pub fn fork(&mut self) -> Self {
+        Self {
+            chain: self.chain.fork(),
+            normalizer: Arc::clone(&self.normalizer),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Flattening large chains on several threads
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use rayon::prelude::*;
+
+use crate::{ChainMap, Recover};
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    /// Same as `collect`, but the layers are copied and merged in parallel
+    ///
+    /// All layers are locked for the whole operation, from the top down.
+    /// Only worth it for chains with several large layers.
+    pub fn par_collect(&self) -> HashMap<K, V> {
+        let mut guards = Vec::new();
+        let mut tombstones = Vec::new();
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            guards.push(m.elem.lock_recover());
+            let mut hidden = HashSet::new();
+            m.hidden_keys(&mut hidden);
+            tombstones.push(hidden);
+            r = m.next.as_ref();
+        }
+        let layers = guards.iter().map(|layer| &**layer).collect::<Vec<_>>();
+        layers
+            .par_iter()
+            .enumerate()
+            .map(|(depth, layer)| {
+                let above = &tombstones[..depth];
+                layer
+                    .iter()
+                    .filter(|(k, _)| !above.iter().any(|hidden| hidden.contains(*k)))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<HashMap<_, _>>()
+            })
+            .reduce(HashMap::new, |mut upper, mut lower| {
+                // Keep the bindings of the upper layers, but extend the larger map
+                if upper.len() >= lower.len() {
+                    for (k, v) in lower {
+                        upper.entry(k).or_insert(v);
+                    }
+                    upper
+                } else {
+                    lower.extend(upper);
+                    lower
+                }
+            })
+    }
+}
@@ -0,0 +1,113 @@
+//! Lock-free lookups for chains that are rarely modified
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::{ChainMap, Node, Ordering, Recover};
+
+/// Copy of the bindings of a layer, as they were at `generation`
+pub(crate) struct Published<K, V> {
+    generation: usize,
+    map: HashMap<K, V>,
+    /// Set by `read_mostly`, so that keys can be copied without requiring `K: Clone`
+    copy_key: fn(&K) -> K,
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Start publishing the bindings of this layer
+    pub(crate) fn publish(&self, copy_key: fn(&K) -> K) {
+        // Stale until the first lookup, which makes the actual copy
+        self.published.get_or_init(|| {
+            ArcSwap::from_pointee(Published {
+                generation: usize::MAX,
+                map: HashMap::new(),
+                copy_key,
+            })
+        });
+    }
+
+    /// Whether lookups read the published copy of this layer
+    pub(crate) fn is_published(&self) -> bool {
+        self.published.get().is_some()
+    }
+
+    fn fresh_copy(&self, copy_key: fn(&K) -> K) -> Published<K, V>
+    where
+        V: Clone,
+    {
+        // Read before copying: a modification made in between makes the copy look stale
+        let generation = self.generation.load(Ordering::Relaxed);
+        let map = self
+            .elem
+            .lock_recover()
+            .iter()
+            .map(|(k, v)| (copy_key(k), v.clone()))
+            .collect();
+        Published {
+            generation,
+            map,
+            copy_key,
+        }
+    }
+
+    /// Key copier of the published copy, `None` if this layer is not published
+    pub(crate) fn published_copier(&self) -> Option<fn(&K) -> K> {
+        Some(self.published.get()?.load().copy_key)
+    }
+
+    /// Up-to-date copy of the bindings, `None` if this layer is not published
+    ///
+    /// Only locks the layer if it was modified since the copy was last published.
+    pub(crate) fn published(&self) -> Option<Arc<Published<K, V>>>
+    where
+        V: Clone,
+    {
+        let published = self.published.get()?;
+        let current = published.load_full();
+        if current.generation == self.generation.load(Ordering::Relaxed) {
+            return Some(current);
+        }
+        let fresh = Arc::new(self.fresh_copy(current.copy_key));
+        published.store(Arc::clone(&fresh));
+        Some(fresh)
+    }
+}
+
+impl<K, V> Published<K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) fn map(&self) -> &HashMap<K, V> {
+        &self.map
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Let lookups read a published copy of each layer instead of locking it
+    ///
+    /// Meant for chains that are set up once and then mostly read: a modification of a layer
+    /// is only copied to its published version by the next lookup, which is the only one that
+    /// locks the layer. Applies to every layer of the chain, and to layers later created above it.
+    pub fn read_mostly(self) -> Self {
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            m.publish(K::clone);
+            r = m.next.as_ref();
+        }
+        self
+    }
+
+    pub fn is_read_mostly(&self) -> bool {
+        self.link().is_published()
+    }
+}
@@ -0,0 +1,155 @@
+//! Limits on the growth of a chain
+
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::rc::Rc;
+
+use crate::{ChainMap, ChainMapError, Node, Recover};
+
+/// Limits enforced by `ChainMap::set_quota`, `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum number of bindings, shadowed ones included
+    pub max_keys: Option<usize>,
+    /// Maximum approximate size of the bindings, counting `size_of::<K>() + size_of::<V>()`
+    /// for each of them: memory owned indirectly by keys and values is not taken into account.
+    pub max_bytes: Option<usize>,
+}
+
+/// An insertion was refused because it would exceed a quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub quota: Quota,
+    /// Number of bindings there would have been after the insertion
+    pub keys: usize,
+    /// Approximate size there would have been after the insertion
+    pub bytes: usize,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Quota exceeded ({} bindings, about {} bytes)",
+            self.keys, self.bytes
+        )
+    }
+}
+
+impl Error for QuotaExceeded {}
+
+/// Quota of a handle, along with the layer it was set on
+pub(crate) struct QuotaScope<K, V>
+where
+    K: Eq + Hash,
+{
+    pub(crate) limits: Quota,
+    pub(crate) base: Rc<Node<K, V>>,
+}
+
+impl<K, V> Clone for QuotaScope<K, V>
+where
+    K: Eq + Hash,
+{
+    fn clone(&self) -> Self {
+        Self {
+            limits: self.limits,
+            base: Rc::clone(&self.base),
+        }
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Limit the growth of the toplevel and of all layers later created on top of it
+    ///
+    /// The quota is inherited by the handles created from this one by `extend` and `fork`.
+    /// Insertions through any of them are refused if they would make the layers between the
+    /// handle's toplevel and the current toplevel (both included) exceed the quota.
+    /// Layers below the current toplevel are not counted, and neither are sibling branches:
+    /// each path from a leaf down to the current toplevel is limited separately.
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = Some(QuotaScope {
+            limits: quota,
+            base: self.settled_link().clone(),
+        });
+    }
+
+    /// Remove the quota of this handle, handles created from it before keep theirs
+    pub fn clear_quota(&mut self) {
+        self.quota = None;
+    }
+
+    pub fn quota(&self) -> Option<Quota> {
+        self.quota.as_ref().map(|scope| scope.limits)
+    }
+
+    /// Same as `insert`, but report failures instead of panicking
+    pub fn try_insert(&mut self, key: K, val: V) -> Result<Option<V>, ChainMapError> {
+        if self.is_locked() {
+            return Err(ChainMapError::Locked);
+        }
+        if self.quota.is_some() {
+            let new = !self.top().elem.lock_recover().contains_key(&key);
+            self.check_quota(new as usize)?;
+        }
+        Ok(self.insert_unchecked(key, val))
+    }
+
+    /// Check that `new` more bindings can be added to the toplevel
+    pub(crate) fn check_quota(&self, new: usize) -> Result<(), QuotaExceeded> {
+        let scope = match &self.quota {
+            Some(scope) => scope,
+            None => return Ok(()),
+        };
+        if new == 0 {
+            return Ok(());
+        }
+        let keys = new + self.quota_usage(scope);
+        let bytes = keys * (mem::size_of::<K>() + mem::size_of::<V>());
+        let over = |max: Option<usize>, n| max.is_some_and(|max| n > max);
+        if over(scope.limits.max_keys, keys) || over(scope.limits.max_bytes, bytes) {
+            Err(QuotaExceeded {
+                quota: scope.limits,
+                keys,
+                bytes,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of bindings that can still be added to the toplevel, `None` if unlimited
+    pub(crate) fn quota_headroom(&self) -> Option<usize> {
+        let scope = self.quota.as_ref()?;
+        let used = self.quota_usage(scope);
+        let size = (mem::size_of::<K>() + mem::size_of::<V>()).max(1);
+        let by_keys = scope.limits.max_keys.map(|max| max.saturating_sub(used));
+        let by_bytes = scope
+            .limits
+            .max_bytes
+            .map(|max| (max / size).saturating_sub(used));
+        match (by_keys, by_bytes) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Number of bindings counted against `scope`
+    fn quota_usage(&self, scope: &QuotaScope<K, V>) -> usize {
+        let mut keys = 0;
+        let mut r = Some(self.link());
+        while let Some(m) = r {
+            keys += m.elem.lock_recover().len();
+            if Rc::ptr_eq(m, &scope.base) {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        keys
+    }
+}
@@ -0,0 +1,142 @@
+//! Learned starting points for lookups in deep chains
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::{ChainMap, Mutex, Node, Ordering, Recover};
+
+/// Effect of the routing table of a handle, see `ChainMap::set_routing`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoutingStats {
+    /// Layers that lookups did not have to probe
+    pub skipped: usize,
+    /// Routes computed because none was known or the chain had changed
+    pub learned: usize,
+}
+
+/// Layer where the keys of a class are first found
+#[derive(Clone)]
+struct Route {
+    /// Generations of the layers above, which held no key of the class
+    above: Vec<usize>,
+    /// Whether any layer holds a key of the class, the route points past the end otherwise
+    found: bool,
+}
+
+#[derive(Clone)]
+struct RoutingState<K> {
+    routes: HashMap<K, Route>,
+    stats: RoutingStats,
+}
+
+/// Maps a key to its class, see `ChainMap::set_routing`
+type Classifier<K> = Rc<dyn Fn(&K) -> Option<K>>;
+
+pub(crate) struct Router<K> {
+    classify: Classifier<K>,
+    state: Mutex<RoutingState<K>>,
+}
+
+impl<K: Clone> Clone for Router<K> {
+    fn clone(&self) -> Self {
+        Self {
+            classify: Rc::clone(&self.classify),
+            state: Mutex::new(self.state.lock_recover().clone()),
+        }
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Let `get_routed` start directly at the layer where keys of the same class are found
+    ///
+    /// `classify` maps a key to its class (e.g. `"builtin.print"` to `"builtin"`), or to `None`
+    /// if it should always be looked up from the toplevel. For each class, the handle remembers
+    /// the highest layer that binds a key of that class; the layers above it can then be skipped
+    /// for as long as their generations show they were not modified.
+    /// Routes are relative to this handle: they are copied by `clone`, but not inherited
+    /// by `extend` or `fork`.
+    pub fn set_routing<F>(&mut self, classify: F)
+    where
+        F: Fn(&K) -> Option<K> + 'static,
+    {
+        self.routing = Some(Router {
+            classify: Rc::new(classify),
+            state: Mutex::new(RoutingState {
+                routes: HashMap::new(),
+                stats: RoutingStats::default(),
+            }),
+        });
+    }
+
+    pub fn clear_routing(&mut self) {
+        self.routing = None;
+    }
+
+    /// `None` if no routing table was set
+    pub fn routing_stats(&self) -> Option<RoutingStats> {
+        let router = self.routing.as_ref()?;
+        let stats = router.state.lock_recover().stats;
+        Some(stats)
+    }
+
+    /// Highest layer that may bind `key` according to the routing table, `None` if none can
+    ///
+    /// An owned copy of `key` is only made if there is a routing table to classify it.
+    pub(crate) fn route<Q>(&self, key: &Q) -> Option<&Rc<Node<K, V>>>
+    where
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        let top = self.link();
+        let router = match &self.routing {
+            Some(router) => router,
+            None => return Some(top),
+        };
+        let class = match (router.classify)(&key.to_owned()) {
+            Some(class) => class,
+            None => return Some(top),
+        };
+        let mut state = router.state.lock_recover();
+        if let Some(route) = state.routes.get(&class) {
+            let mut r = Some(top);
+            let mut valid = true;
+            for &generation in &route.above {
+                match r {
+                    Some(m) if m.generation.load(Ordering::Relaxed) == generation => {
+                        r = m.next.as_ref();
+                    }
+                    _ => {
+                        valid = false;
+                        break;
+                    }
+                }
+            }
+            if valid && r.is_some() == route.found {
+                state.stats.skipped += route.above.len();
+                return r;
+            }
+        }
+        state.stats.learned += 1;
+        let mut r = Some(top);
+        let mut above = Vec::new();
+        while let Some(m) = r {
+            let in_class = |k: &K| (router.classify)(k).as_ref() == Some(&class);
+            // A tombstone for a key of the class must not be skipped either
+            let found = m.elem.lock_recover().keys().any(in_class)
+                || (m.hiding.load(Ordering::Relaxed)
+                    && m.tombstones.lock_recover().iter().any(in_class));
+            if found {
+                break;
+            }
+            above.push(m.generation.load(Ordering::Relaxed));
+            r = m.next.as_ref();
+        }
+        let found = r.is_some();
+        state.routes.insert(class, Route { above, found });
+        r
+    }
+}
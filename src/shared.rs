@@ -0,0 +1,40 @@
+//! Chains of large values, shared rather than cloned by lookups
+
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::ChainMap;
+
+/// Chain whose lookups return a new reference to the value instead of a deep copy
+///
+/// Suited for values that are expensive to clone, e.g. syntax trees: `get` only increments
+/// a reference count. Values can still be modified with `Arc::make_mut` in `update_with`,
+/// which only copies them if they are also referenced from outside the chain.
+pub type SharedChainMap<K, V> = ChainMap<K, Arc<V>>;
+
+impl<K, V> ChainMap<K, Arc<V>>
+where
+    K: Eq + Hash + Clone,
+    V: ?Sized,
+{
+    /// Same as `get`, named to make it clear that the value is not cloned
+    pub fn get_shared<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key)
+    }
+}
+
+impl<K, V> ChainMap<K, Arc<V>>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Create a new binding in the toplevel, wrapping `val` in an `Arc`
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert_shared(&mut self, key: K, val: V) -> Option<Arc<V>> {
+        self.insert(key, Arc::new(val))
+    }
+}
@@ -0,0 +1,100 @@
+//! Periodic capture of the bindings of a chain
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{ChainMap, Clock, Node, SystemClock};
+
+/// All bindings accessible from a chain at a given time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot<K, V>
+where
+    K: Eq + Hash,
+{
+    pub taken_at: Instant,
+    pub bindings: HashMap<K, V>,
+}
+
+/// Schedule created by `ChainMap::start_snapshotting`
+///
+/// Layers are reference-counted with `Rc`, so the chain cannot be captured from another thread:
+/// instead `tick` should be called regularly, e.g. from the main loop of the service,
+/// and hands off a snapshot whenever the interval has elapsed.
+pub struct Snapshotter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    chain: Rc<Node<K, V>>,
+    interval: Duration,
+    clock: Box<dyn Clock>,
+    last: Instant,
+    sink: Box<dyn FnMut(Snapshot<K, V>)>,
+}
+
+impl<K, V> Snapshotter<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Take a snapshot if at least one interval has elapsed since the previous one
+    ///
+    /// Returns whether a snapshot was handed to the sink.
+    pub fn tick(&mut self) -> bool {
+        if self.clock.now().duration_since(self.last) < self.interval {
+            return false;
+        }
+        self.snapshot_now();
+        true
+    }
+
+    /// Take a snapshot immediately, regardless of the interval
+    pub fn snapshot_now(&mut self) {
+        let bindings = ChainMap::from_head(self.chain.clone()).collect();
+        self.last = self.clock.now();
+        (self.sink)(Snapshot {
+            taken_at: self.last,
+            bindings,
+        });
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Periodically hand off the bindings of the chain to `sink`
+    ///
+    /// The first snapshot is taken by the first call to `tick` at least `interval` after this one.
+    /// Snapshots see the chain as it is seen by `self` at the time of the call:
+    /// later bindings made through `self` are included, but not layers added by a later `fork`.
+    pub fn start_snapshotting<F>(&self, interval: Duration, sink: F) -> Snapshotter<K, V>
+    where
+        F: FnMut(Snapshot<K, V>) + 'static,
+    {
+        self.start_snapshotting_with_clock(interval, SystemClock, sink)
+    }
+
+    /// Same as `start_snapshotting`, but with time measured by `clock`
+    pub fn start_snapshotting_with_clock<C, F>(
+        &self,
+        interval: Duration,
+        clock: C,
+        sink: F,
+    ) -> Snapshotter<K, V>
+    where
+        C: Clock + 'static,
+        F: FnMut(Snapshot<K, V>) + 'static,
+    {
+        Snapshotter {
+            chain: self.settled_link().clone(),
+            interval,
+            last: clock.now(),
+            clock: Box::new(clock),
+            sink: Box::new(sink),
+        }
+    }
+}
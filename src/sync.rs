@@ -0,0 +1,343 @@
+//! A chain whose handles can be shared across threads
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(loom)]
+use loom::sync::Condvar;
+#[cfg(not(loom))]
+use std::sync::Condvar;
+
+use crate::{AtomicBool, Mutex, Ordering, Recover};
+
+/// Same as a `ChainMap`, but built on `Arc` so that it is `Send + Sync`
+///
+/// Meant for environments shared by worker threads: layers are already protected
+/// by a `Mutex`, only the links between them need to be atomically reference counted.
+///
+/// Provides the same subset of the `ChainMap` API as `LocalChainMap`, see its documentation,
+/// along with `thread_local_leaf`, `mirror` and `wait_for` for coordinating threads.
+pub struct SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    head: Arc<SyncNode<K, V>>,
+    /// Shared by all handles created from the same root
+    pub(crate) changes: Arc<Changes>,
+}
+
+/// Count of the modifications made to a chain, that readers can wait on
+pub(crate) struct Changes {
+    version: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl Changes {
+    fn new() -> Self {
+        Self {
+            version: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        *self.version.lock_recover() += 1;
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn version(&self) -> usize {
+        *self.version.lock_recover()
+    }
+
+    /// Block until the version is no longer `seen` or `timeout` elapses, return the current version
+    pub(crate) fn wait(&self, seen: usize, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut version = self.version.lock_recover();
+        while *version == seen {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            version = match self.changed.wait_timeout(version, deadline - now) {
+                Ok((version, _)) => version,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        *version
+    }
+}
+
+type SyncLink<K, V> = Option<Arc<SyncNode<K, V>>>;
+
+struct SyncNode<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    elem: Mutex<HashMap<K, V>>,
+    next: SyncLink<K, V>,
+    fallthrough: bool,
+    unlocked: AtomicBool,
+    write_auth: AtomicBool,
+}
+
+impl<K, V> SyncNode<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn new(elem: HashMap<K, V>, next: SyncLink<K, V>, fallthrough: bool) -> Self {
+        Self {
+            elem: Mutex::new(elem),
+            next,
+            fallthrough,
+            unlocked: AtomicBool::new(true),
+            write_auth: AtomicBool::new(true),
+        }
+    }
+}
+
+impl<K, V> SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Create a new empty root
+    pub fn new() -> Self {
+        Self::new_with(HashMap::new())
+    }
+
+    /// Create a new root and initialize with given map
+    pub fn new_with(h: HashMap<K, V>) -> Self {
+        Self {
+            head: Arc::new(SyncNode::new(h, None, false)),
+            changes: Arc::new(Changes::new()),
+        }
+    }
+
+    /// Create a new binding in the toplevel, see `ChainMap::insert`
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        if self.is_locked() {
+            panic!("Map is locked, could not insert");
+        }
+        let old = self.head.elem.lock_recover().insert(key, val);
+        self.changes.notify();
+        old
+    }
+
+    /// Replace old value with new, see `ChainMap::update`
+    /// # Panics
+    /// - if `key` does not already exist
+    /// - if first layer with `key` is locked
+    /// - if `key` is only found after a write-protected layer
+    pub fn update<Q>(&mut self, key: &Q, newval: V)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if !m.write_auth.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some(val) = m.elem.lock_recover().get_mut(key) {
+                if m.unlocked.load(Ordering::Relaxed) {
+                    *val = newval;
+                    self.changes.notify();
+                    return;
+                } else {
+                    panic!("Key is locked, failed to update");
+                }
+            }
+            r = m.next.as_ref();
+        }
+        panic!("Key does not exist, failed to update");
+    }
+
+    /// Retrieve value associated with the first appearance of `key` in the chain
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(val) = m.elem.lock_recover().get(key) {
+                return Some(val.clone());
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Block until `key` is bound in the chain or `timeout` elapses
+    ///
+    /// Meant for initialization-order problems, where a thread needs a binding that another
+    /// thread has yet to publish. Returns `None` on timeout.
+    pub fn wait_for<Q>(&self, key: &Q, timeout: Duration) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Read before the lookup: a binding made in between ends the wait immediately
+            let seen = self.changes.version();
+            if let Some(val) = self.get(key) {
+                return Some(val);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            self.changes.wait(seen, deadline - now);
+        }
+    }
+
+    /// Check associated value only in topmost layers: stops at the first non-fallthrough level
+    pub fn local_get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(val) = m.elem.lock_recover().get(key) {
+                return Some(val.clone());
+            }
+            if !m.fallthrough {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if m.elem.lock_recover().contains_key(key) {
+                return true;
+            }
+            r = m.next.as_ref();
+        }
+        false
+    }
+
+    pub fn extend(&self) -> Self {
+        self.extend_with(HashMap::new())
+    }
+
+    /// Create a new scope, see `ChainMap::extend_with`
+    pub fn extend_with(&self, h: HashMap<K, V>) -> Self {
+        Self {
+            head: Arc::new(SyncNode::new(h, Some(Arc::clone(&self.head)), false)),
+            changes: Arc::clone(&self.changes),
+        }
+    }
+
+    pub fn fork(&mut self) -> Self {
+        self.fork_with(HashMap::new())
+    }
+
+    /// Create a new branch, see `ChainMap::fork_with`
+    pub fn fork_with(&mut self, h: HashMap<K, V>) -> Self {
+        let newlevel = self.extend_with(h);
+        self.head = Arc::new(SyncNode::new(HashMap::new(), Some(Arc::clone(&self.head)), true));
+        newlevel
+    }
+
+    pub fn lock(&mut self) {
+        self.head.unlocked.store(false, Ordering::Relaxed);
+    }
+
+    pub fn unlock(&mut self) {
+        self.head.unlocked.store(true, Ordering::Relaxed);
+    }
+
+    pub fn locked(mut self) -> Self {
+        self.lock();
+        self
+    }
+
+    pub fn unlocked(mut self) -> Self {
+        self.unlock();
+        self
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.head.unlocked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        !self.is_unlocked()
+    }
+
+    /// Resulting layer cannot modify any value lower in the map
+    pub fn readonly(self) -> Self {
+        self.head.write_auth.store(false, Ordering::Relaxed);
+        self
+    }
+
+    /// Another handle on the same toplevel, e.g. to be moved to another thread
+    pub fn clone_linked(&self) -> Self {
+        Self {
+            head: Arc::clone(&self.head),
+            changes: Arc::clone(&self.changes),
+        }
+    }
+
+    /// Gather all bindings in a single `HashMap`, see `ChainMap::collect`
+    pub fn collect(&self) -> HashMap<K, V> {
+        let mut h = HashMap::new();
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            for (key, val) in m.elem.lock_recover().iter() {
+                h.entry(key.clone()).or_insert_with(|| val.clone());
+            }
+            r = m.next.as_ref();
+        }
+        h
+    }
+}
+
+impl<K, V> Clone for SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Copy the toplevel, see `ChainMap::clone`
+    fn clone(&self) -> Self {
+        let node = &self.head;
+        let copy = SyncNode::new(
+            node.elem.lock_recover().clone(),
+            node.next.clone(),
+            node.fallthrough,
+        );
+        copy.unlocked.store(node.unlocked.load(Ordering::Relaxed), Ordering::Relaxed);
+        copy.write_auth.store(node.write_auth.load(Ordering::Relaxed), Ordering::Relaxed);
+        Self {
+            head: Arc::new(copy),
+            changes: Arc::clone(&self.changes),
+        }
+    }
+}
+
+impl<K, V> Default for SyncChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
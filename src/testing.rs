@@ -0,0 +1,164 @@
+//! Deterministic chain builders for tests
+//!
+//! The chains produced here always have the same shape and contents,
+//! which makes them suitable as fixtures for unit tests and as the setup
+//! phase of `loom` models (build with `RUSTFLAGS="--cfg loom"`).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::ChainMap;
+
+/// Build a linear chain of `depth` layers above the root
+///
+/// Returns every handle from the root (index `0`) to the top (index `depth`).
+/// Layer `i` binds `i => i` and shadows `0 => i`, so that
+/// `chain[i].get(&0) == Some(i)` and `chain[i].get(&j) == Some(j)` for all `j <= i`.
+pub fn linear(depth: usize) -> Vec<ChainMap<usize, usize>> {
+    let mut layers = vec![ChainMap::new()];
+    layers[0].insert(0, 0);
+    for i in 1..=depth {
+        let mut layer = layers[i - 1].extend();
+        layer.insert(0, i);
+        layer.insert(i, i);
+        layers.push(layer);
+    }
+    layers
+}
+
+/// Fork `width` branches out of a root binding `0 => 0`
+///
+/// Branch `i` binds `i + 1 => i + 1`. The returned root is the post-fork handle,
+/// so later insertions into it are not visible to any of the branches.
+pub fn forked(width: usize) -> (ChainMap<usize, usize>, Vec<ChainMap<usize, usize>>) {
+    let mut root = ChainMap::new();
+    root.insert(0, 0);
+    let branches = (1..=width)
+        .map(|i| {
+            let mut branch = root.fork();
+            branch.insert(i, i);
+            branch
+        })
+        .collect();
+    (root, branches)
+}
+
+impl ChainMap<usize, usize> {
+    /// Build a chain of `depth` layers above the root, filled with pseudo-random bindings
+    ///
+    /// Every layer binds `keys_per_layer` keys, drawn from a range small enough that layers
+    /// shadow each other. The same `seed` always produces the same chain.
+    /// Returns the top of the chain.
+    pub fn fixture(depth: usize, keys_per_layer: usize, seed: u64) -> Self {
+        let mut state = seed;
+        // splitmix64, good enough to spread keys and stable across platforms
+        let mut next = move || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            (z ^ (z >> 31)) as usize
+        };
+        let range = (depth + 1) * keys_per_layer.max(1);
+        let layer = |next: &mut dyn FnMut() -> usize| {
+            let mut h = HashMap::new();
+            while h.len() < keys_per_layer {
+                h.insert(next() % range, next());
+            }
+            h
+        };
+        let mut chain = ChainMap::new_with(layer(&mut next));
+        for _ in 0..depth {
+            chain = chain.extend_with(layer(&mut next));
+        }
+        chain
+    }
+}
+
+/// Assert that a key resolves to a value, and optionally at which depth
+///
+/// The depth is the distance from the top of the chain to the layer that binds the key,
+/// as reported by `ChainMap::get_traced`.
+///
+/// ```
+/// # use chainmap::{assert_resolves, ChainMap};
+/// let mut root = ChainMap::new();
+/// root.insert("x", 1);
+/// let mut scope = root.extend();
+/// scope.insert("y", 2);
+/// assert_resolves!(scope, "x" => 1 @ 1);
+/// assert_resolves!(scope, "y" => 2);
+/// ```
+#[macro_export]
+macro_rules! assert_resolves {
+    ( $chain:expr, $key:expr => $val:tt @ $depth:expr ) => {{
+        let (val, trace) = $chain.get_traced(&$key);
+        assert_eq!(val, Some($val), "unexpected value\n{}", trace);
+        assert_eq!(trace.resolved_at(), Some($depth), "unexpected depth\n{}", trace);
+    }};
+    ( $chain:expr, $key:expr => $val:expr ) => {{
+        let (val, trace) = $chain.get_traced(&$key);
+        assert_eq!(val, Some($val), "unexpected value\n{}", trace);
+    }};
+}
+
+/// Describe how the bindings accessible from two chains differ, `None` if they do not
+///
+/// Each line reports a key bound on one side only or bound to different values,
+/// along with the depth of the layer that supplies each value. Used by `assert_chain_eq!`.
+pub fn chain_diff<K, V>(left: &ChainMap<K, V>, right: &ChainMap<K, V>) -> Option<String>
+where
+    K: Eq + Hash + Clone + fmt::Debug,
+    V: Clone + PartialEq + fmt::Debug,
+{
+    let describe = |chain: &ChainMap<K, V>, key: &K| {
+        let (val, trace) = chain.get_traced(key);
+        format!("{:?} (depth {})", val.unwrap(), trace.resolved_at().unwrap())
+    };
+    let mut keys = left.key_set();
+    keys.extend(right.key_set());
+    let mut lines = keys
+        .iter()
+        .filter_map(|k| match (left.get(k), right.get(k)) {
+            (Some(_), None) => Some(format!("only in left:  {:?} => {}", k, describe(left, k))),
+            (None, Some(_)) => Some(format!("only in right: {:?} => {}", k, describe(right, k))),
+            (Some(l), Some(r)) if l != r => Some(format!(
+                "differs:       {:?} => {} in left, {} in right",
+                k,
+                describe(left, k),
+                describe(right, k)
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        return None;
+    }
+    lines.sort();
+    Some(lines.join("\n"))
+}
+
+/// Assert that two chains give access to the same bindings, whatever their layers
+///
+/// On failure, the differences are listed as by `chainmap::testing::chain_diff`.
+///
+/// ```
+/// # use chainmap::{assert_chain_eq, ChainMap};
+/// let mut flat = ChainMap::new();
+/// flat.insert("x", 1);
+/// flat.insert("y", 2);
+/// let mut layered = ChainMap::new();
+/// layered.insert("x", 1);
+/// let mut layered = layered.extend();
+/// layered.insert("y", 2);
+/// assert_chain_eq!(flat, layered);
+/// ```
+#[macro_export]
+macro_rules! assert_chain_eq {
+    ( $left:expr, $right:expr $(,)? ) => {{
+        if let Some(diff) = $crate::testing::chain_diff(&$left, &$right) {
+            panic!("assertion failed: chains differ\n{}", diff);
+        }
+    }};
+}
@@ -0,0 +1,84 @@
+//! Record of how a lookup went through the chain
+
+use std::fmt;
+
+/// Why a lookup stopped at or went past a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Outcome {
+    /// The layer binds the key, the lookup stops here
+    Found,
+    /// The layer does not bind the key, the lookup continues below
+    Missing,
+    /// The layer hides the key from the layers below, the lookup stops here
+    Masked,
+    /// The layer does not bind the key and is not fallthrough, `local_get` stops here
+    ScopeStop,
+    /// The layer may not be modified, `update` stops here whether it binds the key or not
+    ReadOnlyStop,
+}
+
+/// Which operation a trace follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    Get,
+    Local,
+    Update,
+}
+
+/// A single layer visited during a lookup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    /// Distance from the top of the chain, the toplevel has depth `0`
+    pub depth: usize,
+    pub outcome: Outcome,
+    pub fallthrough: bool,
+    pub locked: bool,
+    pub readonly: bool,
+}
+
+/// All layers visited during a lookup, from top to bottom
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolutionTrace {
+    pub(crate) steps: Vec<TraceStep>,
+}
+
+impl ResolutionTrace {
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+
+    /// Depth of the layer that resolved the lookup, if any
+    pub fn resolved_at(&self) -> Option<usize> {
+        self.steps
+            .iter()
+            .find(|step| step.outcome == Outcome::Found)
+            .map(|step| step.depth)
+    }
+}
+
+impl fmt::Display for ResolutionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            write!(f, "layer {}: ", step.depth)?;
+            match step.outcome {
+                Outcome::Found => write!(f, "found")?,
+                Outcome::Missing => write!(f, "missing")?,
+                Outcome::Masked => write!(f, "masked")?,
+                Outcome::ScopeStop => write!(f, "end of scope")?,
+                Outcome::ReadOnlyStop => write!(f, "write-protected")?,
+            }
+            for (flag, name) in &[
+                (step.fallthrough, "fallthrough"),
+                (step.locked, "locked"),
+                (step.readonly, "readonly"),
+            ] {
+                if *flag {
+                    write!(f, " [{}]", name)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
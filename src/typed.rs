@@ -0,0 +1,57 @@
+//! Well-known bindings accessed through marker types
+
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::hash::Hash;
+
+use crate::ChainMap;
+
+/// Marker type standing for a fixed key, along with the type of its value
+///
+/// ```
+/// # use chainmap::{ChainMap, TypedKey};
+/// struct Verbosity;
+/// impl TypedKey for Verbosity {
+///     const NAME: &'static str = "verbosity";
+///     type Value = u8;
+/// }
+///
+/// let mut ch = ChainMap::<String, u8>::new();
+/// ch.insert_typed::<Verbosity>(2);
+/// assert_eq!(ch.get_typed::<Verbosity>(), Some(2));
+/// assert_eq!(ch.get_str("verbosity"), Some(2));
+/// ```
+pub trait TypedKey {
+    /// Key of the binding in the chain
+    const NAME: &'static str;
+    type Value;
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + Borrow<str> + for<'a> From<&'a str>,
+    V: Clone,
+{
+    /// Create a new binding in the toplevel for the key named by `T`
+    /// # Panics
+    /// Panics if toplevel map is locked
+    pub fn insert_typed<T>(&mut self, val: T::Value)
+    where
+        T: TypedKey,
+        V: From<T::Value>,
+    {
+        self.insert_str(T::NAME, V::from(val));
+    }
+
+    /// Value associated with the key named by `T`
+    ///
+    /// A value that cannot be converted to `T::Value` is treated as absent.
+    pub fn get_typed<T>(&self) -> Option<T::Value>
+    where
+        T: TypedKey,
+        T::Value: TryFrom<V>,
+    {
+        self.get_str(T::NAME)
+            .and_then(|val| T::Value::try_from(val).ok())
+    }
+}
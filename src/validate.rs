@@ -0,0 +1,80 @@
+//! Consistency checks over the layers of a chain
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use crate::{ChainMap, Ordering, Recover, GENERATION};
+
+/// A broken invariant found by `ChainMap::validate`
+///
+/// Layers are designated by their distance from the top of the chain, the toplevel has depth `0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Issue {
+    /// The layer is reachable from itself, traversals of the chain would never end
+    Cycle { depth: usize },
+    /// The layer claims a generation that has not been handed out yet,
+    /// so its modifications may go unnoticed by `collect_cached`
+    GenerationAhead { depth: usize },
+    /// The last layer is fallthrough, which leaves `local_get` nowhere to stop
+    FallthroughRoot { depth: usize },
+    /// The caching layer keeps a copy of a key it also binds itself
+    CachedOwnBinding { depth: usize },
+    /// A panic occurred while the layer was locked, it may have been left half-modified
+    Poisoned { depth: usize },
+    /// The layer has tombstones but is not marked as hiding keys, lookups ignore them
+    UnmarkedTombstones { depth: usize },
+    /// The layer both binds and hides the same key
+    HiddenOwnBinding { depth: usize },
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Check the structural invariants of all layers visible from this handle
+    ///
+    /// Intended for debug builds of applications that build complex trees of layers,
+    /// an empty result means that no problem was found.
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let mut visited = HashSet::new();
+        let current = GENERATION.load(Ordering::Relaxed);
+        let mut r = Some(self.link());
+        let mut depth = self.link_depth();
+        while let Some(m) = r {
+            if !visited.insert(Rc::as_ptr(m)) {
+                issues.push(Issue::Cycle { depth });
+                break;
+            }
+            if m.generation.load(Ordering::Relaxed) >= current {
+                issues.push(Issue::GenerationAhead { depth });
+            }
+            if m.fallthrough && m.next.is_none() {
+                issues.push(Issue::FallthroughRoot { depth });
+            }
+            match m.elem.lock() {
+                Err(_) => issues.push(Issue::Poisoned { depth }),
+                Ok(layer) => {
+                    if let Some(cache) = &m.cache {
+                        if cache.lock_recover().entries.keys().any(|k| layer.contains_key(k)) {
+                            issues.push(Issue::CachedOwnBinding { depth });
+                        }
+                    }
+                    let tombstones = m.tombstones.lock_recover();
+                    if !tombstones.is_empty() && !m.hiding.load(Ordering::Relaxed) {
+                        issues.push(Issue::UnmarkedTombstones { depth });
+                    }
+                    if tombstones.iter().any(|k| layer.contains_key(k)) {
+                        issues.push(Issue::HiddenOwnBinding { depth });
+                    }
+                }
+            }
+            depth += 1;
+            r = m.next.as_ref();
+        }
+        issues
+    }
+}
@@ -0,0 +1,232 @@
+//! A chain of `Vec`s indexed by dense integer keys
+
+use std::rc::Rc;
+
+use crate::{AtomicBool, Mutex, Ordering, Recover};
+
+/// Same as a `ChainMap<usize, V>`, but each layer is a `Vec<Option<V>>`
+///
+/// Suited for compilers that resolve identifiers to small slot indices ahead of time:
+/// looking up a slot in a layer is a plain array access instead of a hash lookup.
+/// Memory usage of each layer is proportional to its largest bound slot.
+pub struct ChainVecMap<V>
+where
+    V: Clone,
+{
+    head: Rc<VecNode<V>>,
+}
+
+type VecLink<V> = Option<Rc<VecNode<V>>>;
+
+struct VecNode<V>
+where
+    V: Clone,
+{
+    elem: Mutex<Vec<Option<V>>>,
+    next: VecLink<V>,
+    fallthrough: bool,
+    unlocked: AtomicBool,
+    write_auth: AtomicBool,
+}
+
+impl<V> VecNode<V>
+where
+    V: Clone,
+{
+    fn new(elem: Vec<Option<V>>, next: VecLink<V>, fallthrough: bool) -> Self {
+        Self {
+            elem: Mutex::new(elem),
+            next,
+            fallthrough,
+            unlocked: AtomicBool::new(true),
+            write_auth: AtomicBool::new(true),
+        }
+    }
+}
+
+impl<V> ChainVecMap<V>
+where
+    V: Clone,
+{
+    fn top(&self) -> &VecNode<V> {
+        &self.head
+    }
+
+    /// Create a new empty root
+    pub fn new() -> Self {
+        Self::new_with(Vec::new())
+    }
+
+    /// Create a new root and initialize with given slots
+    pub fn new_with(slots: Vec<Option<V>>) -> Self {
+        Self {
+            head: Rc::new(VecNode::new(slots, None, false)),
+        }
+    }
+
+    /// Create a new binding in the toplevel
+    /// # Panics
+    /// Panics if toplevel is locked
+    pub fn insert(&mut self, slot: usize, val: V) {
+        if self.is_locked() {
+            panic!("Map is locked, could not insert");
+        }
+        let mut slots = self.top().elem.lock_recover();
+        if slots.len() <= slot {
+            slots.resize(slot + 1, None);
+        }
+        slots[slot] = Some(val);
+    }
+
+    pub fn lock(&mut self) {
+        self.top().unlocked.store(false, Ordering::Relaxed);
+    }
+
+    pub fn unlock(&mut self) {
+        self.top().unlocked.store(true, Ordering::Relaxed);
+    }
+
+    pub fn locked(mut self) -> Self {
+        self.lock();
+        self
+    }
+
+    pub fn unlocked(mut self) -> Self {
+        self.unlock();
+        self
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.top().unlocked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        !self.is_unlocked()
+    }
+
+    /// Resulting layer cannot modify any value lower in the map
+    pub fn readonly(self) -> Self {
+        self.top().write_auth.store(false, Ordering::Relaxed);
+        self
+    }
+
+    /// Retrieve value associated with the first appearance of `slot` in the chain
+    pub fn get(&self, slot: usize) -> Option<V> {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(Some(val)) = m.elem.lock_recover().get(slot) {
+                return Some(val.clone());
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Check associated value only in topmost layers: stops at the first non-fallthrough level
+    pub fn local_get(&self, slot: usize) -> Option<V> {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if let Some(Some(val)) = m.elem.lock_recover().get(slot) {
+                return Some(val.clone());
+            }
+            if !m.fallthrough {
+                break;
+            }
+            r = m.next.as_ref();
+        }
+        None
+    }
+
+    /// Replace old value with new, see `ChainMap::update`
+    /// # Panics
+    /// - if `slot` is not bound
+    /// - if first layer with `slot` is locked
+    /// - if `slot` is only found after a write-protected layer
+    pub fn update(&mut self, slot: usize, newval: V) {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if !m.write_auth.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some(Some(val)) = m.elem.lock_recover().get_mut(slot) {
+                if m.unlocked.load(Ordering::Relaxed) {
+                    *val = newval;
+                    return;
+                } else {
+                    panic!("Key is locked, failed to update");
+                }
+            }
+            r = m.next.as_ref();
+        }
+        panic!("Key does not exist, failed to update");
+    }
+
+    /// Replace old value with new, create binding in toplevel if `update` would fail
+    pub fn update_or(&mut self, slot: usize, newval: V) {
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            if !m.write_auth.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Some(Some(val)) = m.elem.lock_recover().get_mut(slot) {
+                if m.unlocked.load(Ordering::Relaxed) {
+                    *val = newval;
+                    return;
+                } else {
+                    break;
+                }
+            }
+            r = m.next.as_ref();
+        }
+        self.insert(slot, newval);
+    }
+
+    pub fn extend(&self) -> Self {
+        self.extend_with(Vec::new())
+    }
+
+    /// Create a new scope, see `ChainMap::extend_with`
+    pub fn extend_with(&self, slots: Vec<Option<V>>) -> Self {
+        Self {
+            head: Rc::new(VecNode::new(slots, Some(Rc::clone(&self.head)), false)),
+        }
+    }
+
+    pub fn fork(&mut self) -> Self {
+        self.fork_with(Vec::new())
+    }
+
+    /// Create a new branch, see `ChainMap::fork_with`
+    pub fn fork_with(&mut self, slots: Vec<Option<V>>) -> Self {
+        let newlevel = self.extend_with(slots);
+        self.head = Rc::new(VecNode::new(Vec::new(), Some(Rc::clone(&self.head)), true));
+        newlevel
+    }
+
+    /// Gather all slots in a single `Vec`, see `ChainMap::collect`
+    pub fn collect(&self) -> Vec<Option<V>> {
+        let mut slots: Vec<Option<V>> = Vec::new();
+        let mut r = Some(&self.head);
+        while let Some(m) = r {
+            for (i, val) in m.elem.lock_recover().iter().enumerate() {
+                if slots.len() <= i {
+                    slots.resize(i + 1, None);
+                }
+                if slots[i].is_none() {
+                    slots[i] = val.clone();
+                }
+            }
+            r = m.next.as_ref();
+        }
+        slots
+    }
+}
+
+impl<V> Default for ChainVecMap<V>
+where
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
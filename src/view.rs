@@ -0,0 +1,142 @@
+//! Read-only projections of a chain
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::{ChainMap, FlatCache, Mutex, Recover};
+
+/// Filtered and mapped view of the bindings of a chain, see `ChainMap::derive_view`
+pub struct DerivedView<K2, V2> {
+    generations: Box<dyn Fn() -> Vec<usize>>,
+    compute: Box<dyn Fn() -> HashMap<K2, V2>>,
+    cache: Mutex<Option<FlatCache<K2, V2>>>,
+}
+
+impl<K2, V2> DerivedView<K2, V2>
+where
+    K2: Eq + Hash,
+    V2: Clone,
+{
+    /// Current contents of the view, only recomputed if the chain was modified since last time
+    pub fn snapshot(&self) -> Arc<HashMap<K2, V2>> {
+        let generations = (self.generations)();
+        let mut cache = self.cache.lock_recover();
+        if let Some(cache) = &*cache {
+            if cache.generations == generations {
+                return Arc::clone(&cache.map);
+            }
+        }
+        let map = Arc::new((self.compute)());
+        *cache = Some(FlatCache {
+            generations,
+            map: Arc::clone(&map),
+        });
+        map
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V2>
+    where
+        K2: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.snapshot().get(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K2: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.snapshot().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshot().is_empty()
+    }
+}
+
+/// Chain whose keys are translated before each lookup, see `ChainMap::view_remapped`
+pub struct RemappedView<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    chain: ChainMap<K, V>,
+    remap: Box<dyn Fn(&K) -> K>,
+}
+
+impl<K, V> RemappedView<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Value associated with the translation of `key` in the underlying chain
+    ///
+    /// The translation takes an owned key, which is made from `key` first.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.chain.get(&(self.remap)(&key.to_owned()))
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.chain.contains_key(&(self.remap)(&key.to_owned()))
+    }
+}
+
+impl<K, V> ChainMap<K, V>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: Clone + 'static,
+{
+    /// Project the accessible bindings through `f`, dropping those for which it returns `None`
+    ///
+    /// The view is only computed when it is read, and computed again only after a layer of
+    /// the chain was modified. It sees the chain as it is seen by `self` at the time of the call:
+    /// later bindings made through `self` are included, but not layers added by a later `fork`.
+    /// If `f` maps several bindings to the same key, which one is kept is unspecified.
+    pub fn derive_view<K2, V2, F>(&self, f: F) -> DerivedView<K2, V2>
+    where
+        K2: Eq + Hash,
+        F: Fn(&K, &V) -> Option<(K2, V2)> + 'static,
+    {
+        let chain = Rc::new(Self::from_head(self.settled_link().clone()));
+        let source = Rc::clone(&chain);
+        DerivedView {
+            generations: Box::new(move || chain.generations()),
+            compute: Box::new(move || {
+                source
+                    .collect()
+                    .iter()
+                    .filter_map(|(k, v)| f(k, v))
+                    .collect()
+            }),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Read-only access to the chain through keys translated by `f`
+    ///
+    /// Lets several logical namespaces share one chain, e.g. by having `f` add a tenant prefix.
+    /// No binding is copied: as for `derive_view`, later bindings made through `self` are seen
+    /// by the view, but not layers added by a later `fork`.
+    pub fn view_remapped<F>(&self, f: F) -> RemappedView<K, V>
+    where
+        F: Fn(&K) -> K + 'static,
+    {
+        RemappedView {
+            chain: Self::from_head(self.settled_link().clone()),
+            remap: Box::new(f),
+        }
+    }
+}